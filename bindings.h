@@ -3,11 +3,39 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+/**
+ * Maximum `Limit` accepted by DescribeFirewallRules
+ */
+#define FIREWALL_RULES_PAGE_SIZE 100
+
+/**
+ * Version of the state file written by this build. Files of a newer version are refused
+ */
+#define STATE_VERSION 1
+
+/**
+ * Configuration file and profile of the target a [`WebClient`] was created for, opaque to C
+ */
+typedef struct TargetConfig TargetConfig;
+
 typedef struct WebClient {
   char *tmp_file_path;
   char *instance_id;
   char *token_id;
   char *token_key;
+  /**
+   * Empty to fall back to REGION variable or the default region
+   */
+  char *region;
+  /**
+   * Empty to fall back to ENDPOINT variable or the default endpoint
+   */
+  char *endpoint;
+  /**
+   * Null unless created from a configuration file, whose ip detection, retry policy, rate
+   * limits, state file and audit log are then used
+   */
+  struct TargetConfig *target_config;
 } WebClient;
 
 typedef struct UserAgentNative {
@@ -19,7 +47,11 @@ typedef struct UserAgentNative {
 
 typedef struct IpConfigNative {
   const char *ip;
-  uint32_t ip_decimal;
+  /**
+   * Decimal digits of the ip as a number, which doesn't fit an integer type of C for an
+   * IPv6 address
+   */
+  const char *ip_decimal;
   const char *country;
   const char *country_iso;
   uint8_t country_eu;
@@ -50,6 +82,10 @@ const char *rust_version(void);
 
 /**
  * Create WebClient. For C, it creates a WebClient struct pointer
+ *
+ * The state file is kept in the directory of `tmp_file_path` unless STATE_FILE variable
+ * names another file.
+ * Returns null if any argument is null or not valid UTF-8.
  * # Safety
  */
 struct WebClient *create_webapi_client(const char *tmp_file_path,
@@ -57,6 +93,20 @@ struct WebClient *create_webapi_client(const char *tmp_file_path,
                                        const char *token_id,
                                        const char *token_key);
 
+/**
+ * Create WebClient for a target of the configuration file. For C, it creates a WebClient
+ * struct pointer
+ *
+ * `config_path` may be null to use `~/.config/update_qcloud_firewall/config.toml`. Besides
+ * the profile of the target, the ip detection, rate limits, state file and audit log of the
+ * configuration are used.
+ * Returns null if the configuration can't be loaded or doesn't define the target.
+ * # Safety
+ */
+struct WebClient *create_webapi_client_from_config(const char *tmp_file_path,
+                                                   const char *config_path,
+                                                   const char *target);
+
 /**
  * .
  *
@@ -85,28 +135,25 @@ void free_swapi_client(struct WebClient *client);
 void free_string(char *s);
 
 /**
- * .
+ * Get the public ip config through `outer_listener`
  *
- * # Panics
- *
- * Panics if .
+ * A null client is reported through `onError`.
  *
  * # Safety
  *
- * .
+ * `client` must be null or a client created by this library and not freed yet.
  */
 void get_ip_config_native(struct WebClient *client, struct IpConfigCallback outer_listener);
 
 /**
- * .
- *
- * # Panics
+ * Recreate the firewall rules of `payload` for the current public ip through
+ * `outer_listener`
  *
- * Panics if .
+ * A null client or an invalid payload is reported through `onError`.
  *
  * # Safety
  *
- * .
+ * `client` must be null or a client created by this library and not freed yet.
  */
 void recreate_firewall_policy(struct WebClient *client,
                               const char *payload,
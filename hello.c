@@ -24,24 +24,35 @@ int main(int argc, char **argv) {
     // printf("version: %s\n", ver);
     printf("Creating webapi client\n");
     printf("%s\n", P_tmpdir);
-    // get token_id & token_key from .env file
-    env_load(".", false);
-    char *token_id = getenv("SECRETID");
-    char *token_key = getenv("SECRETKEY");
-    if (NULL == token_id || NULL == token_key) {
-        printf("Error: Failed to get SECRETID or SECRETKEY from .env file!\n");
-        return 1;
-    }
-    // printf("%s\n", token_id);
-    // printf("%s\n", token_key);
     char tmp_file_path[PATH_MAX];
-    char *instance_id = "lhins-3jq1gki4";
     char *file_name = "update_qcloud_firewall_ip.txt";
     snprintf(tmp_file_path, strlen(P_tmpdir) + strlen(file_name) + 2, "%s/%s",
              P_tmpdir, file_name);
     printf("Tmp file will be saved into %s\n", tmp_file_path);
-    struct WebClient *webClient =
-        create_webapi_client(tmp_file_path, instance_id, token_id, token_key);
+    struct WebClient *webClient = NULL;
+    if (argc > 1) {
+        // ./hello <target> takes instance and credentials from
+        // ~/.config/update_qcloud_firewall/config.toml
+        webClient =
+            create_webapi_client_from_config(tmp_file_path, NULL, argv[1]);
+    } else {
+        // get token_id, token_key & instance_id from .env file
+        env_load(".", false);
+        char *token_id = getenv("SECRETID");
+        char *token_key = getenv("SECRETKEY");
+        char *instance_id = getenv("INSTANCEID");
+        if (NULL == token_id || NULL == token_key || NULL == instance_id) {
+            printf("Error: Failed to get SECRETID, SECRETKEY or INSTANCEID "
+                   "from .env file!\n");
+            return 1;
+        }
+        webClient = create_webapi_client(tmp_file_path, instance_id, token_id,
+                                         token_key);
+    }
+    if (NULL == webClient) {
+        printf("Error: Failed to create webapi client!\n");
+        return 1;
+    }
     //
     // IpConfigCallback callback = {
     //     .owner = webClient, .onResult = on_result, .onError = on_error};
@@ -0,0 +1,255 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{request::FirewallRule, IpFamily, QCloudError};
+
+/// Something that happened to the public ip or the firewall of an instance
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// Public ip detected for the instance differs from the one detected before. Recorded
+    /// before the ip is applied, whether that succeeds or not
+    IpChanged {
+        instance_id: String,
+        family: IpFamily,
+        previous: Option<String>,
+        ip: String,
+        /// Providers which reported `ip`
+        providers: Vec<String>,
+    },
+    /// CreateFirewallRules request succeeded
+    RulesCreated {
+        instance_id: String,
+        rules: Vec<FirewallRule>,
+        request_id: Option<String>,
+    },
+    /// DeleteFirewallRules request succeeded
+    RulesDeleted {
+        instance_id: String,
+        rules: Vec<FirewallRule>,
+        request_id: Option<String>,
+    },
+}
+
+impl AuditEvent {
+    /// Instance the event is about
+    pub fn instance_id(&self) -> &str {
+        match self {
+            AuditEvent::IpChanged { instance_id, .. }
+            | AuditEvent::RulesCreated { instance_id, .. }
+            | AuditEvent::RulesDeleted { instance_id, .. } => instance_id,
+        }
+    }
+}
+
+/// Line of the audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// e.g. `2024-05-01 12:00:00 lhins-xxxxxxxx created 2 rule(s), RequestId req-1`, in local time
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ",
+            self.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+            self.event.instance_id()
+        )?;
+        match &self.event {
+            AuditEvent::IpChanged {
+                family,
+                previous,
+                ip,
+                providers,
+                ..
+            } => write!(
+                f,
+                "{family} changed from {} to {ip} according to {}",
+                previous.as_deref().unwrap_or("none"),
+                providers.join(", ")
+            ),
+            AuditEvent::RulesCreated {
+                rules, request_id, ..
+            } => write!(
+                f,
+                "created {} rule(s), RequestId {}",
+                rules.len(),
+                request_id.as_deref().unwrap_or("-")
+            ),
+            AuditEvent::RulesDeleted {
+                rules, request_id, ..
+            } => write!(
+                f,
+                "deleted {} rule(s), RequestId {}",
+                rules.len(),
+                request_id.as_deref().unwrap_or("-")
+            ),
+        }
+    }
+}
+
+/// Entries of the audit log to return, all of them by default
+#[derive(Default, Debug, Clone)]
+pub struct AuditFilter {
+    /// Only entries at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Only entries before this time
+    pub until: Option<DateTime<Utc>>,
+    /// Only entries of these instances, any instance if empty
+    pub instance_ids: Vec<String>,
+}
+
+impl AuditFilter {
+    /// Whether `entry` is in the time range and of one of the instances
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.since.is_none_or(|since| entry.time >= since)
+            && self.until.is_none_or(|until| entry.time < until)
+            && (self.instance_ids.is_empty()
+                || self
+                    .instance_ids
+                    .iter()
+                    .any(|id| id == entry.event.instance_id()))
+    }
+}
+
+/// Append-only JSON Lines file of ip changes and firewall modifications, for answering when
+/// an ip changed and which rules were rewritten
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Log at `path`, falling back to AUDIT_LOG variable and then [`AuditLog::default_path`]
+    pub fn new(path: Option<String>) -> Self {
+        let path = path
+            .or_else(|| dotenv::var("AUDIT_LOG").ok())
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(Self::default_path);
+        Self { path }
+    }
+
+    /// `audit.jsonl` next to the default state file
+    pub fn default_path() -> PathBuf {
+        crate::StateStore::default_path().with_file_name("audit.jsonl")
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `event` as a line stamped with the current time
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the log can't be written.
+    pub fn append(&self, event: AuditEvent) -> Result<(), QCloudError> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let entry = AuditEntry {
+            time: Utc::now(),
+            event,
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        // a single write to a file opened for appending, so concurrent runs don't interleave
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Entries matching `filter`, oldest first. Lines which aren't valid entries, e.g. one cut
+    /// short by a crash, are skipped
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the log exists but can't be read.
+    pub fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>, QCloudError> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            if let Ok(entry) = serde_json::from_str::<AuditEntry>(&line?) {
+                if filter.matches(&entry) {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log_is_queried_by_time_and_instance() {
+        let dir = std::env::temp_dir().join(format!(
+            "update_qcloud_firewall_audit_{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let log = AuditLog::new(Some(dir.join("audit.jsonl").display().to_string()));
+        assert!(log.query(&AuditFilter::default()).unwrap().is_empty());
+
+        let start = Utc::now();
+        log.append(AuditEvent::IpChanged {
+            instance_id: "lhins-a".to_string(),
+            family: IpFamily::Ipv4,
+            previous: Some("1.1.1.1".to_string()),
+            ip: "2.2.2.2".to_string(),
+            providers: vec!["https://api.ipify.org".to_string()],
+        })
+        .unwrap();
+        log.append(AuditEvent::RulesCreated {
+            instance_id: "lhins-b".to_string(),
+            rules: Vec::new(),
+            request_id: Some("req-1".to_string()),
+        })
+        .unwrap();
+
+        let all = log.query(&AuditFilter::default()).unwrap();
+        assert_eq!(all.len(), 2);
+        assert!(all[0]
+            .to_string()
+            .contains("IPv4 changed from 1.1.1.1 to 2.2.2.2"));
+        assert!(all[1]
+            .to_string()
+            .contains("created 0 rule(s), RequestId req-1"));
+
+        let filter = AuditFilter {
+            instance_ids: vec!["lhins-b".to_string()],
+            ..Default::default()
+        };
+        let entries = log.query(&filter).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].event.instance_id(), "lhins-b");
+
+        let filter = AuditFilter {
+            until: Some(start),
+            ..Default::default()
+        };
+        assert!(log.query(&filter).unwrap().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,55 +1,863 @@
-use std::path::Path;
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+    process::ExitCode,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, TimeZone, Utc};
 
-use clap::{command, Parser};
-use tokio::{fs::File, io::AsyncReadExt};
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::{stream, StreamExt};
+use tokio::{
+    fs::File,
+    io::AsyncReadExt,
+    signal::unix::{signal, SignalKind},
+};
 use update_qcloud_firewall::{
-    request::CreateDeleteFirewallRulesRequest,
-    IpTools, QCloudTool,
+    request::CreateDeleteFirewallRulesRequest, response::FirewallRuleSet, AuditFilter, AuditLog,
+    Config, Drift, FirewallPlan, FirewallRuleFilter, IpDetection, IpProvider, IpTools, Profile,
+    PublicIps, QCloudTool, StateStore,
 };
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Name of the person to greet
-    #[arg(short, long)]
-    payload_json_file: String,
+    /// Region of the instance, e.g. ap-guangzhou. Falls back to REGION variable
+    #[arg(short, long, global = true)]
+    region: Option<String>,
+    /// Endpoint host or base URL, e.g. lighthouse.ap-guangzhou.tencentcloudapi.com.
+    /// Falls back to ENDPOINT variable
+    #[arg(short, long, global = true)]
+    endpoint: Option<String>,
+    /// Lighthouse instance id, repeat to update several instances. Falls back to INSTANCEID
+    /// variable if no --target is given either
+    #[arg(short, long, global = true)]
+    instance: Vec<String>,
+    /// Configuration file. Defaults to ~/.config/update_qcloud_firewall/config.toml if it exists
+    #[arg(short, long, global = true)]
+    config: Option<String>,
+    /// Profile of the configuration file to take credentials and region of --instance from
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Target of the configuration file to take instance, payload template and profile from,
+    /// repeat to update several targets
+    #[arg(short, long, global = true)]
+    target: Vec<String>,
+    /// Use every target of the configuration file
+    #[arg(long, global = true)]
+    all_targets: bool,
+    /// Maximum number of instances worked on at the same time
+    #[arg(short = 'j', long, global = true, default_value_t = 4)]
+    parallelism: usize,
+    /// Take the public ip from this local network interface (e.g. pppoe-wan) instead of
+    /// asking external providers
+    #[arg(long, global = true)]
+    interface: Option<String>,
+    /// State file recording what has been applied to each instance. Falls back to state_file
+    /// of the configuration, STATE_FILE variable and
+    /// ~/.local/state/update_qcloud_firewall/state.json
+    #[arg(long, global = true)]
+    state_file: Option<String>,
+    /// Audit log recording ip changes and firewall modifications. Falls back to audit_log of
+    /// the configuration, AUDIT_LOG variable and audit.jsonl next to the state file
+    #[arg(long, global = true)]
+    audit_log: Option<String>,
+    #[command(subcommand)]
+    command: Command,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let secret_id = dotenv::var("SECRETID").unwrap();
-    let secret_key = dotenv::var("SECRETKEY").unwrap();
-    let instance_id = dotenv::var("INSTANCEID").unwrap();
-    let args = Args::parse();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show the rules to add and remove for the current public ip, exit with 2 if changes are
+    /// pending
+    Plan {
+        /// Payload template with the rules to allow the public ip. Defaults to the templates of
+        /// the targets
+        #[arg(short, long)]
+        payload_json_file: Option<String>,
+        /// Output format, `json` prints an array with the plan of each instance
+        #[arg(short, long, value_enum, default_value_t = PlanFormat::Human)]
+        output: PlanFormat,
+    },
+    /// Add and remove rules so the firewall allows the current public ip
+    Apply {
+        /// Payload template with the rules to allow the public ip. Defaults to the templates of
+        /// the targets
+        #[arg(short, long)]
+        payload_json_file: Option<String>,
+        /// Skip interactive approval of the plan
+        #[arg(long)]
+        auto_approve: bool,
+    },
+    /// List the rules of the firewall
+    List {
+        /// Only rules whose description contains this text (case insensitive)
+        #[arg(long)]
+        description: Option<String>,
+        /// Only rules of this protocol, e.g. TCP
+        #[arg(long)]
+        protocol: Option<String>,
+        /// Only rules of this port, e.g. 443 or 8000-9000
+        #[arg(long)]
+        port: Option<String>,
+        /// Only rules of this cidr block
+        #[arg(long)]
+        cidr: Option<String>,
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = ListFormat::Table)]
+        output: ListFormat,
+    },
+    /// Export the rules of the firewall as a payload template
+    Export {
+        /// Only rules whose description contains this text (case insensitive)
+        #[arg(long)]
+        description: Option<String>,
+        /// File to write the template into instead of stdout
+        #[arg(long)]
+        output_file: Option<String>,
+    },
+    /// Poll the public ip and apply the templates whenever it changes. SIGHUP reloads the
+    /// configuration
+    Daemon {
+        /// Payload template with the rules to allow the public ip. Defaults to the templates of
+        /// the targets
+        #[arg(short, long)]
+        payload_json_file: Option<String>,
+        /// Seconds between two checks of the public ip
+        #[arg(long, default_value_t = 300)]
+        interval: u64,
+        /// Up to this many seconds are randomly added to each interval
+        #[arg(long, default_value_t = 30)]
+        jitter: u64,
+        /// Compare the live firewall with the templates at every check, repairing rules
+        /// deleted or edited in the console even if the public ip is unchanged
+        #[arg(long)]
+        check_drift: bool,
+    },
+    /// Show ip changes and firewall modifications of the audit log, of --instance and
+    /// --target only if given
+    History {
+        /// Only entries from this date (YYYY-MM-DD, local time) or time (RFC 3339) on
+        #[arg(long)]
+        since: Option<String>,
+        /// Only entries up to this date (YYYY-MM-DD, inclusive) or before this time (RFC 3339)
+        #[arg(long)]
+        until: Option<String>,
+        /// Output format, JSON being one entry per line
+        #[arg(short, long, value_enum, default_value_t = PlanFormat::Human)]
+        output: PlanFormat,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PlanFormat {
+    Human,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ListFormat {
+    Table,
+    Json,
+    Yaml,
+}
+
+/// Render rules as a table with aligned columns
+fn rules_table(rules: &[FirewallRuleSet]) -> String {
+    let header = ["PROTOCOL", "PORT", "CIDR", "ACTION", "DESCRIPTION"];
+    let rows: Vec<[&str; 5]> = rules
+        .iter()
+        .map(|rule| {
+            [
+                rule.protocol.as_str(),
+                rule.port.as_deref().unwrap_or("ALL"),
+                rule.ipv6_cidr_block
+                    .as_deref()
+                    .filter(|cidr| !cidr.is_empty())
+                    .unwrap_or(&rule.cidr_block),
+                rule.action.as_deref().unwrap_or_default(),
+                rule.firewall_rule_description
+                    .as_deref()
+                    .unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let mut widths = header.map(|title| title.chars().count());
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{cell}{}", " ".repeat(width - cell.chars().count())))
+            .collect::<Vec<String>>()
+            .join("  ");
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
 
-    let mut payload_file = File::open(args.payload_json_file).await?;
+async fn read_payload(
+    payload_json_file: &str,
+) -> Result<CreateDeleteFirewallRulesRequest, Box<dyn std::error::Error>> {
+    let mut payload_file = File::open(payload_json_file).await?;
     let mut request_payload = String::new();
     payload_file.read_to_string(&mut request_payload).await?;
+    Ok(serde_json::from_str(&request_payload)?)
+}
 
+/// Text file the last ip was kept in before the state file
+fn legacy_ip_file() -> String {
     let tmp_dir = std::env::temp_dir();
     let tmp_ip_file = Path::new(&tmp_dir).join("update_qcloud_firewall_ip.txt");
-    let iptools = IpTools::new(tmp_ip_file.to_string_lossy().to_string());
+    tmp_ip_file.to_string_lossy().to_string()
+}
 
-    let ip_address = iptools.get_china_ip_address().await?;
-    if !(iptools.check_ip_changed(&ip_address).await?) {
-        println!("Nothing happened since ip address doesn't change.");
-        return Ok(());
+fn ip_tools(args: &Args, config: Option<&Config>) -> IpTools {
+    let mut detector = config
+        .map(|config| config.ip_detection.clone())
+        .unwrap_or_default();
+    // the interface replaces the providers, the expected prefixes still apply
+    if let Some(name) = &args.interface {
+        detector.preferred = Vec::new();
+        detector.providers = vec![IpProvider::interface(name)];
+        detector.quorum = Some(1);
+    }
+    IpTools::with_detector(legacy_ip_file(), detector)
+}
+
+/// State file given by --state-file or the configuration, migrating the legacy ip file
+fn state_store(args: &Args, config: Option<&Config>) -> StateStore {
+    let path = args
+        .state_file
+        .clone()
+        .or_else(|| config.and_then(|config| config.state_file.clone()));
+    StateStore::new(path, Some(legacy_ip_file()))
+}
+
+/// Audit log given by --audit-log or the configuration
+fn audit_log(args: &Args, config: Option<&Config>) -> AuditLog {
+    let path = args
+        .audit_log
+        .clone()
+        .or_else(|| config.and_then(|config| config.audit_log.clone()));
+    AuditLog::new(path)
+}
+
+/// Start of the day of `YYYY-MM-DD` in local time (of the next day with `end_of_day`), or an
+/// RFC 3339 time
+fn parse_time(value: &str, end_of_day: bool) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("{value} is neither YYYY-MM-DD nor an RFC 3339 time."))?;
+    let date = if end_of_day {
+        date.succ_opt()
+            .ok_or_else(|| format!("{value} is out of range."))?
+    } else {
+        date
+    };
+    Local
+        .from_local_datetime(&date.and_time(NaiveTime::MIN))
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| format!("{value} doesn't exist in local time."))
+}
+
+/// Print the entries of the audit log matching the time range, --instance and --target
+fn history(
+    args: &Args,
+    since: &Option<String>,
+    until: &Option<String>,
+    output: PlanFormat,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let config = load_config(args)?;
+    let mut instance_ids = args.instance.clone();
+    if !args.target.is_empty() {
+        let config = config
+            .as_ref()
+            .ok_or("--target needs a configuration file.")?;
+        for name in args.target.iter() {
+            instance_ids.push(config.target(name)?.instance_id.clone());
+        }
+    }
+    let filter = AuditFilter {
+        since: since.as_deref().map(|v| parse_time(v, false)).transpose()?,
+        until: until.as_deref().map(|v| parse_time(v, true)).transpose()?,
+        instance_ids,
+    };
+    let entries = audit_log(args, config.as_ref()).query(&filter)?;
+    for entry in entries.iter() {
+        match output {
+            PlanFormat::Human => println!("{entry}"),
+            PlanFormat::Json => println!("{}", serde_json::to_string(entry)?),
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Configuration file given by --config, or the default one if it exists
+fn load_config(args: &Args) -> Result<Option<Config>, Box<dyn std::error::Error>> {
+    Ok(match &args.config {
+        Some(path) => Some(Config::load(Path::new(path))?),
+        None => match Config::default_path().filter(|path| path.exists()) {
+            Some(path) => Some(Config::load(&path)?),
+            None => None,
+        },
+    })
+}
+
+/// Ask on the terminal whether the plan should be applied
+fn approved() -> io::Result<bool> {
+    print!("Do you want to apply these changes? Only 'yes' will be accepted: ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
+/// Account, instance and template to work with, resolved from the command line, the
+/// configuration file and the environment in this order
+struct Context {
+    qcloud_tool: QCloudTool,
+    instance_id: String,
+    payload_template: Option<String>,
+}
+
+impl Context {
+    /// One context per --target and per --instance, or one for INSTANCEID variable if neither
+    /// is given
+    fn resolve_all(
+        args: &Args,
+        config: Option<&Config>,
+    ) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        let mut contexts = Vec::new();
+        if !args.target.is_empty() || args.all_targets {
+            let config = config.ok_or("--target needs a configuration file.")?;
+            let names: Vec<String> = if args.all_targets {
+                config.targets.keys().cloned().collect()
+            } else {
+                args.target.clone()
+            };
+            for name in names.iter() {
+                let target = config.target(name)?;
+                let profile = config.target_profile(target)?;
+                contexts.push(Self {
+                    qcloud_tool: QCloudTool::from_profile(&with_overrides(profile, args)),
+                    instance_id: target.instance_id.clone(),
+                    payload_template: target.payload_template.clone(),
+                });
+            }
+        }
+
+        if !args.instance.is_empty() || contexts.is_empty() {
+            let profile = match (config, &args.profile) {
+                (Some(config), Some(name)) => Some(config.profile(Some(name))?.clone()),
+                (None, Some(_)) => return Err("--profile needs a configuration file.".into()),
+                // without explicit choice, the default profile is used if there is one
+                (Some(config), None) => config.profile(None).ok().cloned(),
+                (None, None) => None,
+            };
+            let qcloud_tool = match profile {
+                Some(profile) => QCloudTool::from_profile(&with_overrides(profile, args)),
+                None => QCloudTool::new(None, None, args.region.clone(), args.endpoint.clone())?,
+            };
+            let instances = if args.instance.is_empty() {
+                vec![dotenv::var("INSTANCEID").map_err(|_| {
+                    "Please specify --instance, --target or INSTANCEID variable in .env file."
+                })?]
+            } else {
+                args.instance.clone()
+            };
+            for instance_id in instances {
+                contexts.push(Self {
+                    qcloud_tool: qcloud_tool.clone(),
+                    instance_id,
+                    payload_template: None,
+                });
+            }
+        }
+        Ok(contexts)
+    }
+
+    /// The only context, for commands working on a single instance
+    fn single(mut contexts: Vec<Self>) -> Result<Self, Box<dyn std::error::Error>> {
+        if contexts.len() != 1 {
+            return Err("This command works on a single instance.".into());
+        }
+        Ok(contexts.remove(0))
+    }
+
+    /// Plan the template of this context, or `payload_json_file` if given
+    async fn plan(
+        &self,
+        payload_json_file: &Option<String>,
+        ips: &PublicIps,
+    ) -> Result<FirewallPlan, Box<dyn std::error::Error>> {
+        let payload_json_file = payload_json_file
+            .clone()
+            .or_else(|| self.payload_template.clone())
+            .ok_or("Please specify --payload-json-file or a --target with a template.")?;
+        let request = read_payload(&payload_json_file).await?;
+        Ok(self
+            .qcloud_tool
+            .plan_firewall_rules(&self.instance_id, &request, ips)
+            .await?)
+    }
+}
+
+/// Apply --region and --endpoint to `profile`
+fn with_overrides(mut profile: Profile, args: &Args) -> Profile {
+    if args.region.is_some() {
+        profile.region = args.region.clone();
+    }
+    if args.endpoint.is_some() {
+        profile.endpoint = args.endpoint.clone();
     }
+    profile
+}
+
+/// Plan all contexts, at most `parallelism` at a time, keeping the order of `contexts`
+async fn plan_all(
+    contexts: &[Context],
+    payload_json_file: &Option<String>,
+    ips: &PublicIps,
+    parallelism: usize,
+) -> Vec<Result<FirewallPlan, Box<dyn std::error::Error>>> {
+    let mut plans: Vec<_> = stream::iter(contexts.iter().enumerate())
+        .map(|(i, context)| async move { (i, context.plan(payload_json_file, ips).await) })
+        .buffer_unordered(parallelism.max(1))
+        .collect()
+        .await;
+    plans.sort_by_key(|(i, _)| *i);
+    plans.into_iter().map(|(_, plan)| plan).collect()
+}
+
+/// Apply all plans, at most `parallelism` at a time, keeping the order of `contexts`. New rules
+/// are created and verified before the stale ones are removed
+async fn apply_all<'a>(
+    contexts: &[Context],
+    plans: &'a [Result<FirewallPlan, Box<dyn std::error::Error>>],
+    parallelism: usize,
+) -> Vec<Result<&'a FirewallPlan, String>> {
+    let mut results: Vec<_> = stream::iter(contexts.iter().zip(plans.iter()).enumerate())
+        .map(|(i, (context, plan))| async move {
+            let result = match plan {
+                Ok(plan) if plan.is_empty() => Ok(plan),
+                Ok(plan) => context
+                    .qcloud_tool
+                    .apply_firewall_plan(plan)
+                    .await
+                    .map(|_| plan)
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(err.to_string()),
+            };
+            (i, result)
+        })
+        .buffer_unordered(parallelism.max(1))
+        .collect()
+        .await;
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Print the outcome of each instance. Returns the number of failed instances
+fn report(contexts: &[Context], results: &[Result<&FirewallPlan, String>]) -> usize {
+    let mut failed = 0;
+    for (context, result) in contexts.iter().zip(results) {
+        match result {
+            Ok(plan) if plan.is_empty() => println!("{}: no changes", context.instance_id),
+            Ok(plan) => println!(
+                "{}: applied, {} added, {} removed",
+                context.instance_id,
+                plan.to_add.len(),
+                plan.to_remove.len()
+            ),
+            Err(err) => {
+                failed += 1;
+                eprintln!("{}: failed: {err}", context.instance_id);
+            }
+        }
+    }
+    failed
+}
 
-    let qcloud_tool = QCloudTool::new(Some(secret_id), Some(secret_key));
+/// Everything a run works with, resolved from the command line and the configuration
+struct Setup {
+    contexts: Vec<Context>,
+    iptools: IpTools,
+    store: StateStore,
+    audit_log: AuditLog,
+}
 
-    // we remove existing firewall rules.
-    let request: CreateDeleteFirewallRulesRequest = serde_json::from_str(&request_payload).unwrap();
-    let _res = qcloud_tool
-        .remove_firewall_rules(&instance_id, &request)
-        .await?;
+/// Contexts, ip detection, state file and audit log of the configuration. Create and delete
+/// requests of all contexts are recorded in the audit log
+fn load(args: &Args) -> Result<Setup, Box<dyn std::error::Error>> {
+    let config = load_config(args)?;
+    config
+        .as_ref()
+        .map(|config| config.rate_limits.clone())
+        .unwrap_or_default()
+        .apply_globally();
+    let audit_log = audit_log(args, config.as_ref());
+    let contexts = Context::resolve_all(args, config.as_ref())?
+        .into_iter()
+        .map(|context| Context {
+            qcloud_tool: context.qcloud_tool.with_audit_log(audit_log.clone()),
+            ..context
+        })
+        .collect();
+    Ok(Setup {
+        contexts,
+        iptools: ip_tools(args, config.as_ref()),
+        store: state_store(args, config.as_ref()),
+        audit_log,
+    })
+}
 
-    // we create new firewall rules with new public ip in cidr field
-    let _res = qcloud_tool
-        .create_firewall_rules(&instance_id, &request, &ip_address)
-        .await?;
+/// Print `message` with a timestamp, for the log of the daemon
+fn log(message: &str) {
+    println!("[{}] {message}", Local::now().format("%Y-%m-%d %H:%M:%S"));
+}
 
-    iptools.save_ip_into_file(&ip_address).await?;
+/// Note the detected ips of every context in the state file and the ones that changed in the
+/// audit log. Done before applying, so a change is recorded even if applying it fails
+fn note_detections(
+    setup: &Setup,
+    detections: &[IpDetection],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let changes = setup.store.update(|state| {
+        let mut changes = Vec::new();
+        for context in setup.contexts.iter() {
+            changes.extend(state.note_detections(&context.instance_id, detections));
+        }
+        changes
+    })?;
+    for change in changes {
+        if let Err(err) = setup.audit_log.append(change) {
+            eprintln!("Failed to write audit log: {err}");
+        }
+    }
+    Ok(())
+}
 
+/// Record `ips` and the rules of each successfully applied plan in the state file
+async fn record_state(
+    setup: &Setup,
+    detections: &[IpDetection],
+    results: &[Result<&FirewallPlan, String>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ips = PublicIps::from_detections(detections);
+    let mut applied = Vec::new();
+    for (context, result) in setup.contexts.iter().zip(results) {
+        if let Ok(plan) = result {
+            // the version only helps to tell later changes apart, it isn't worth failing for
+            let version = context
+                .qcloud_tool
+                .firewall_version(&context.instance_id)
+                .await
+                .ok()
+                .flatten();
+            applied.push((*plan, version));
+        }
+    }
+    setup.store.update(|state| {
+        for (plan, version) in applied {
+            state.record(plan, &ips, version);
+        }
+    })?;
+    Ok(())
+}
+
+/// Reconcile all contexts if the public ip differs from the one last applied to any of them,
+/// or `force` is set. With `check_drift`, the firewalls are compared with the templates even if
+/// the ip is unchanged and reconciled if they drifted
+async fn reconcile_on_change(
+    setup: &Setup,
+    payload_json_file: &Option<String>,
+    parallelism: usize,
+    force: bool,
+    check_drift: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let contexts = &setup.contexts;
+    let detections = setup.iptools.detect_public_ips().await?;
+    let ips = PublicIps::from_detections(&detections);
+    let state = setup.store.load()?;
+    let changed = force
+        || contexts
+            .iter()
+            .any(|context| state.ip_changed(&context.instance_id, &ips));
+    if !changed && !check_drift {
+        return Ok(());
+    }
+    if changed {
+        note_detections(setup, &detections)?;
+    }
+    let plans = plan_all(contexts, payload_json_file, &ips, parallelism).await;
+    if changed {
+        for detection in detections.iter() {
+            log(&format!(
+                "Public ip is {} according to {}.",
+                detection.ip,
+                detection.providers.join(", ")
+            ));
+        }
+    } else {
+        let mut drifted = false;
+        let mut failed = 0;
+        for (context, plan) in contexts.iter().zip(plans.iter()) {
+            match plan {
+                Ok(plan) => {
+                    let last_seen = state.instances.get(&context.instance_id);
+                    if let Some(drift) = Drift::detect(plan, last_seen) {
+                        log(drift.to_string().trim_end());
+                        drifted = true;
+                    }
+                }
+                Err(err) => {
+                    failed += 1;
+                    log(&format!("{}: failed to plan: {err}", context.instance_id));
+                }
+            }
+        }
+        if !drifted {
+            return match failed {
+                0 => Ok(()),
+                _ => Err(format!("{failed} of {} instance(s) failed.", contexts.len()).into()),
+            };
+        }
+    }
+    log(&format!("Reconciling {} instance(s).", contexts.len()));
+    for plan in plans.iter().flatten() {
+        print!("{plan}");
+    }
+    let results = apply_all(contexts, &plans, parallelism).await;
+    let failed = report(contexts, &results);
+    // failed instances keep their previous ip, so the next iteration tries them again
+    record_state(setup, &detections, &results).await?;
+    if failed > 0 {
+        return Err(format!("{failed} of {} instance(s) failed.", contexts.len()).into());
+    }
     Ok(())
 }
+
+/// Poll the public ip every `interval` plus up to `jitter`, reconciling the firewall when it
+/// changes, or when it drifts from the templates with `check_drift`. SIGHUP reloads the
+/// configuration, SIGTERM and Ctrl-C stop the daemon
+async fn daemon(
+    args: &Args,
+    payload_json_file: &Option<String>,
+    interval: Duration,
+    jitter: Duration,
+    check_drift: bool,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let mut setup = load(args)?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    log(&format!(
+        "Watching public ip of {} instance(s) every {}s.",
+        setup.contexts.len(),
+        interval.as_secs()
+    ));
+
+    // the firewall is checked at start up even if the cached ip is unchanged
+    let mut force = true;
+    loop {
+        match reconcile_on_change(
+            &setup,
+            payload_json_file,
+            args.parallelism,
+            force,
+            check_drift,
+        )
+        .await
+        {
+            Ok(()) => force = false,
+            Err(err) => log(&format!("Error: {err}")),
+        }
+
+        let delay = interval + jitter.mul_f64(rand::random::<f64>());
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = sighup.recv() => match load(args) {
+                Ok(reloaded) => {
+                    setup = reloaded;
+                    force = true;
+                    log(&format!(
+                        "Configuration reloaded, {} instance(s).",
+                        setup.contexts.len()
+                    ));
+                }
+                Err(err) => log(&format!(
+                    "Failed to reload configuration, keeping the previous one. Err: {err}"
+                )),
+            },
+            _ = sigterm.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    log("Stopped.");
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn run(args: Args) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    if let Command::Daemon {
+        payload_json_file,
+        interval,
+        jitter,
+        check_drift,
+    } = &args.command
+    {
+        let interval = Duration::from_secs(*interval);
+        let jitter = Duration::from_secs(*jitter);
+        return daemon(&args, payload_json_file, interval, jitter, *check_drift).await;
+    }
+    if let Command::History {
+        since,
+        until,
+        output,
+    } = &args.command
+    {
+        return history(&args, since, until, *output);
+    }
+    let setup = load(&args)?;
+    let (contexts, iptools) = (&setup.contexts, &setup.iptools);
+
+    match args.command {
+        Command::Plan {
+            payload_json_file,
+            output,
+        } => {
+            let detections = iptools.detect_public_ips().await?;
+            let ips = PublicIps::from_detections(&detections);
+            let plans = plan_all(contexts, &payload_json_file, &ips, args.parallelism).await;
+            let mut failed = false;
+            let mut pending = false;
+            let mut json_plans = Vec::new();
+            for (context, plan) in contexts.iter().zip(plans) {
+                match plan {
+                    Ok(plan) => {
+                        pending |= !plan.is_empty();
+                        match output {
+                            PlanFormat::Human => print!("{plan}"),
+                            PlanFormat::Json => json_plans.push(plan),
+                        }
+                    }
+                    Err(err) => {
+                        failed = true;
+                        eprintln!("{}: failed to plan: {err}", context.instance_id);
+                    }
+                }
+            }
+            if let PlanFormat::Json = output {
+                println!("{}", serde_json::to_string_pretty(&json_plans)?);
+            }
+            if failed {
+                Ok(ExitCode::FAILURE)
+            } else if pending {
+                Ok(ExitCode::from(2))
+            } else {
+                Ok(ExitCode::SUCCESS)
+            }
+        }
+        Command::Apply {
+            payload_json_file,
+            auto_approve,
+        } => {
+            let detections = iptools.detect_public_ips().await?;
+            let ips = PublicIps::from_detections(&detections);
+            let plans = plan_all(contexts, &payload_json_file, &ips, args.parallelism).await;
+            for (context, plan) in contexts.iter().zip(plans.iter()) {
+                match plan {
+                    Ok(plan) => print!("{plan}"),
+                    Err(err) => eprintln!("{}: failed to plan: {err}", context.instance_id),
+                }
+            }
+            let pending = plans.iter().flatten().any(|plan| !plan.is_empty());
+            if pending && !auto_approve && !approved()? {
+                println!("Apply cancelled.");
+                return Ok(ExitCode::FAILURE);
+            }
+            // a cancelled apply leaves the state and audit log as they were
+            note_detections(&setup, &detections)?;
+
+            let results = apply_all(contexts, &plans, args.parallelism).await;
+            let failed = report(contexts, &results);
+            record_state(&setup, &detections, &results).await?;
+            if failed > 0 {
+                eprintln!("{failed} of {} instance(s) failed.", contexts.len());
+                return Ok(ExitCode::FAILURE);
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::List {
+            description,
+            protocol,
+            port,
+            cidr,
+            output,
+        } => {
+            let context = Context::single(setup.contexts)?;
+            let filter = FirewallRuleFilter {
+                description,
+                protocol,
+                port,
+                cidr,
+            };
+            let rules = filter.apply(
+                context
+                    .qcloud_tool
+                    .list_firewall_rules(&context.instance_id)
+                    .await?,
+            );
+            match output {
+                ListFormat::Table => print!("{}", rules_table(&rules)),
+                ListFormat::Json => println!("{}", serde_json::to_string_pretty(&rules)?),
+                ListFormat::Yaml => print!("{}", serde_yaml::to_string(&rules)?),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Export {
+            description,
+            output_file,
+        } => {
+            let context = Context::single(setup.contexts)?;
+            let tpl = context
+                .qcloud_tool
+                .export_firewall_rules_template(&context.instance_id, description)
+                .await?;
+            let tpl = serde_json::to_string_pretty(&tpl)?;
+            match output_file {
+                Some(output_file) => {
+                    tokio::fs::write(&output_file, tpl + "\n").await?;
+                    println!("Template written into {output_file}");
+                }
+                None => println!("{tpl}"),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Daemon { .. } | Command::History { .. } => {
+            unreachable!("daemon and history are run before resolving contexts")
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(args).await {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
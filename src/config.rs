@@ -0,0 +1,234 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{IpDetector, QCloudError, RateLimits, RetryPolicy};
+
+/// Configuration file with named profiles and targets, e.g.
+///
+/// ```toml
+/// default_profile = "home"
+/// state_file = "/etc/update_qcloud_firewall/state.json"
+///
+/// [profiles.home]
+/// secret_id = "AKID..."
+/// secret_key = "..."
+/// region = "ap-guangzhou"
+///
+/// [targets.nas]
+/// profile = "home"
+/// instance_id = "lhins-xxxxxxxx"
+/// payload_template = "/etc/update_qcloud_firewall/nas.json"
+///
+/// [ip_detection]
+/// quorum = 2
+/// allow = ["203.0.113.0/24"]
+/// providers = [
+///     { kind = "http", url = "https://setb.cn/ip.json", json_path = "publicip" },
+///     { kind = "http", url = "https://api.ipify.org" },
+/// ]
+/// ```
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    /// Profile used when neither the command line nor a target names one. A profile named
+    /// `default` is used when this is unset.
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default)]
+    pub targets: BTreeMap<String, Target>,
+    /// Providers asked for the public ip, all built-in HTTP providers if unset
+    #[serde(default)]
+    pub ip_detection: IpDetector,
+    /// State file recording what has been applied, see [`crate::StateStore::new`]
+    pub state_file: Option<String>,
+    /// Audit log of ip changes and firewall modifications, see [`crate::AuditLog::new`]
+    pub audit_log: Option<String>,
+    /// Requests per second sent by all clients of the process, see [`RateLimits`]
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+}
+
+/// Tencent Cloud account and where to reach it
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub secret_id: String,
+    pub secret_key: String,
+    pub region: Option<String>,
+    /// Endpoint host or base URL
+    pub endpoint: Option<String>,
+    /// Retries of calls failing with a transient error, see [`RetryPolicy`]
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+/// Lighthouse instance and the rules it should get
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    /// Profile of the account owning the instance, the default profile if unset
+    pub profile: Option<String>,
+    pub instance_id: String,
+    /// Path of the payload template
+    pub payload_template: Option<String>,
+    /// Region of the instance if it differs from the region of the profile
+    pub region: Option<String>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/update_qcloud_firewall/config.toml`, falling back to
+    /// `~/.config/update_qcloud_firewall/config.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(
+            config_home
+                .join("update_qcloud_firewall")
+                .join("config.toml"),
+        )
+    }
+
+    /// Parse a configuration file
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be read or isn't valid.
+    pub fn load(path: &Path) -> Result<Self, QCloudError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| QCloudError::Config(format!("failed to read {}: {e}", path.display())))?;
+        Self::parse(&content).map_err(|e| QCloudError::Config(format!("{}: {e}", path.display())))
+    }
+
+    /// Parse configuration from TOML text
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `content` isn't a valid configuration.
+    pub fn parse(content: &str) -> Result<Self, QCloudError> {
+        toml::from_str(content).map_err(|e| QCloudError::Config(e.to_string()))
+    }
+
+    /// Profile called `name`, or the default profile if `name` is None
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the profile doesn't exist.
+    pub fn profile(&self, name: Option<&str>) -> Result<&Profile, QCloudError> {
+        let name = name
+            .or(self.default_profile.as_deref())
+            .unwrap_or("default");
+        self.profiles
+            .get(name)
+            .ok_or_else(|| QCloudError::Config(format!("profile {name} is not defined")))
+    }
+
+    /// Target called `name`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the target doesn't exist.
+    pub fn target(&self, name: &str) -> Result<&Target, QCloudError> {
+        self.targets
+            .get(name)
+            .ok_or_else(|| QCloudError::Config(format!("target {name} is not defined")))
+    }
+
+    /// Profile of `target` with the region of the target applied
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the profile of the target doesn't exist.
+    pub fn target_profile(&self, target: &Target) -> Result<Profile, QCloudError> {
+        let mut profile = self.profile(target.profile.as_deref())?.clone();
+        if target.region.is_some() {
+            profile.region = target.region.clone();
+        }
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+default_profile = "home"
+
+[profiles.home]
+secret_id = "home-id"
+secret_key = "home-key"
+region = "ap-guangzhou"
+
+[profiles.work]
+secret_id = "work-id"
+secret_key = "work-key"
+endpoint = "http://127.0.0.1:8080"
+
+[targets.nas]
+instance_id = "lhins-nas"
+payload_template = "nas.json"
+region = "ap-hongkong"
+
+[targets.office]
+profile = "work"
+instance_id = "lhins-office"
+"#;
+
+    #[test]
+    fn test_profile_falls_back_to_default_profile() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(config.profile(None).unwrap().secret_id, "home-id");
+        assert_eq!(config.profile(Some("work")).unwrap().secret_id, "work-id");
+        assert!(matches!(
+            config.profile(Some("missing")),
+            Err(QCloudError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_target_profile_applies_target_region() {
+        let config = Config::parse(CONFIG).unwrap();
+        let nas = config.target("nas").unwrap();
+        let profile = config.target_profile(nas).unwrap();
+        assert_eq!(profile.secret_id, "home-id");
+        assert_eq!(profile.region.as_deref(), Some("ap-hongkong"));
+
+        let office = config.target("office").unwrap();
+        let profile = config.target_profile(office).unwrap();
+        assert_eq!(profile.secret_id, "work-id");
+        assert_eq!(profile.endpoint.as_deref(), Some("http://127.0.0.1:8080"));
+        assert_eq!(office.payload_template, None);
+    }
+
+    #[test]
+    fn test_ip_detection_defaults_to_builtin_providers() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(config.ip_detection, IpDetector::default());
+
+        let config = Config::parse(
+            r#"
+[ip_detection]
+quorum = 1
+providers = [{ kind = "http", url = "https://example.com/ip", json_path = "ip" }]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.ip_detection.quorum, Some(1));
+        assert_eq!(
+            config.ip_detection.providers,
+            vec![crate::IpProvider::http_json("https://example.com/ip", "ip")]
+        );
+    }
+
+    #[test]
+    fn test_invalid_config_is_config_error() {
+        assert!(matches!(
+            Config::parse("[profiles.home]\nsecret_id = 1"),
+            Err(QCloudError::Config(_))
+        ));
+    }
+}
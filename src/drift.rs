@@ -0,0 +1,123 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    firewall_plan::RuleLine, request::FirewallRule, rule_fingerprint, FirewallPlan, InstanceState,
+};
+
+/// Difference between the live firewall and the template found while the public ip is
+/// unchanged, e.g. because rules were deleted or edited in the console
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Drift {
+    pub instance_id: String,
+    /// Rules of the template missing in the firewall
+    pub missing: Vec<FirewallRule>,
+    /// Live rules with a template description which the template doesn't produce
+    pub unexpected: Vec<FirewallRule>,
+    /// State recorded when the firewall was last reconciled, if ever
+    pub last_seen: Option<InstanceState>,
+}
+
+impl Drift {
+    /// Drift shown by `plan`, None if the firewall matches the template
+    pub fn detect(plan: &FirewallPlan, last_seen: Option<&InstanceState>) -> Option<Self> {
+        if plan.is_empty() {
+            return None;
+        }
+        Some(Self {
+            instance_id: plan.instance_id.clone(),
+            missing: plan.to_add.clone(),
+            unexpected: plan.to_remove.clone(),
+            last_seen: last_seen.cloned(),
+        })
+    }
+
+    /// Whether `rule` was in place at the last reconciliation, i.e. it has been deleted or
+    /// edited since
+    fn was_applied(&self, rule: &FirewallRule) -> bool {
+        self.last_seen
+            .as_ref()
+            .is_some_and(|last_seen| last_seen.rules.contains(&rule_fingerprint(rule)))
+    }
+}
+
+/// One line per drifted rule prefixed with `+` (missing) or `-` (unexpected), after the last
+/// state seen
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Instance {} drifted from the template.",
+            self.instance_id
+        )?;
+        match self.last_seen.as_ref().and_then(|last_seen| {
+            last_seen
+                .reconciled_at
+                .map(|at| (at, last_seen.firewall_version))
+        }) {
+            Some((at, Some(version))) => writeln!(
+                f,
+                "  Last seen matching at {}, firewall version {version}.",
+                at.to_rfc3339()
+            )?,
+            Some((at, None)) => writeln!(f, "  Last seen matching at {}.", at.to_rfc3339())?,
+            None => writeln!(f, "  Never seen matching.")?,
+        }
+        for rule in self.missing.iter() {
+            if self.was_applied(rule) {
+                writeln!(f, "  + {} (deleted or edited since)", RuleLine(rule))?;
+            } else {
+                writeln!(f, "  + {}", RuleLine(rule))?;
+            }
+        }
+        for rule in self.unexpected.iter() {
+            writeln!(f, "  - {}", RuleLine(rule))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::rule;
+
+    #[test]
+    fn test_drift_reports_rules_deleted_since_last_seen() {
+        let ssh = rule("ssh", "1.1.1.1");
+        let plan = FirewallPlan {
+            instance_id: "lhins-a".to_string(),
+            unchanged: vec![ssh.clone()],
+            ..Default::default()
+        };
+        assert_eq!(Drift::detect(&plan, None), None);
+
+        let last_seen = InstanceState {
+            rules: vec![rule_fingerprint(&ssh)],
+            firewall_version: Some(3),
+            reconciled_at: Some(chrono::Utc::now()),
+            ..Default::default()
+        };
+        let plan = FirewallPlan {
+            instance_id: "lhins-a".to_string(),
+            to_add: vec![ssh],
+            to_remove: vec![rule("ssh", "0.0.0.0/0")],
+            ..Default::default()
+        };
+        let drift = Drift::detect(&plan, Some(&last_seen)).unwrap();
+        let report = drift.to_string();
+        assert!(report.contains("firewall version 3"), "{report}");
+        assert!(
+            report.contains("+ TCP 443 from 1.1.1.1 ACCEPT \"ssh\" (deleted or edited since)"),
+            "{report}"
+        );
+        assert!(
+            report.contains("- TCP 443 from 0.0.0.0/0 ACCEPT \"ssh\""),
+            "{report}"
+        );
+
+        let drift = Drift::detect(&plan, None).unwrap();
+        assert!(drift.to_string().contains("Never seen matching."));
+    }
+}
@@ -3,7 +3,9 @@ use std::ffi::{c_char, c_float};
 #[repr(C)]
 pub struct IpConfigNative {
     pub ip: *const c_char,
-    pub ip_decimal: u32,
+    /// Decimal digits of the ip as a number, which doesn't fit an integer type of C for an
+    /// IPv6 address
+    pub ip_decimal: *const c_char,
     pub country: *const c_char,
     pub country_iso: *const c_char,
     pub country_eu: u8,
@@ -19,8 +19,26 @@ pub struct FirewallRule {
     pub port: Option<String>,
     #[serde(rename = "CidrBlock", skip_serializing_if = "Option::is_none")]
     pub cidr_block: Option<String>,
+    #[serde(
+        rename = "Ipv6CidrBlock",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ipv6_cidr_block: Option<String>,
     #[serde(rename = "Action")]
     pub action: Option<String>,
     #[serde(rename = "FirewallRuleDescription")]
     pub firewall_rule_description: Option<String>,
 }
+
+// DescribeFirewallRulesRequest
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DescribeFirewallRulesRequest {
+    #[serde(rename = "InstanceId")]
+    pub instance_id: String,
+    #[serde(rename = "Offset")]
+    pub offset: i64,
+    #[serde(rename = "Limit")]
+    pub limit: i64,
+}
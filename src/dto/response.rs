@@ -10,6 +10,24 @@ pub struct Error {
     pub message: String,
 }
 
+// Common Response envelope: {"Response": {...}}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseRoot<T> {
+    #[serde(rename = "Response")]
+    pub response: T,
+}
+
+// Fields carried by every Response
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommonResponse {
+    #[serde(rename = "Error")]
+    pub error: Option<Error>,
+    #[serde(rename = "RequestId")]
+    pub request_id: Option<String>,
+}
+
 // CreateDeleteFirewallRulesResponse
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -60,8 +78,14 @@ pub struct FirewallRuleSet {
     pub protocol: String,
     #[serde(rename = "Port")]
     pub port: Option<String>,
-    #[serde(rename = "CidrBlock")]
+    #[serde(rename = "CidrBlock", default)]
     pub cidr_block: String,
+    #[serde(
+        rename = "Ipv6CidrBlock",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ipv6_cidr_block: Option<String>,
     #[serde(rename = "Action")]
     pub action: Option<String>,
     #[serde(rename = "FirewallRuleDescription")]
@@ -5,7 +5,8 @@ use serde::{Deserialize, Serialize};
 // #[serde(rename_all = "camelCase")]
 pub struct IpInfo {
     pub ip: String,
-    pub ip_decimal: u32,
+    /// The ip as a number, which takes 128 bits for an IPv6 address
+    pub ip_decimal: u128,
     pub country: String,
     pub country_iso: String,
     pub country_eu: bool,
@@ -26,10 +27,3 @@ pub struct UserAgent {
     pub version: String,
     pub raw_value: String,
 }
-
-#[derive(Serialize, Deserialize)]
-pub struct SetBIpInfo {
-    pub publicip: String,
-}
-
-
@@ -0,0 +1,90 @@
+use std::{error, fmt, io};
+
+/// Errors returned by this crate
+#[derive(Debug)]
+pub enum QCloudError {
+    /// Request couldn't be sent or its response couldn't be received
+    Transport(reqwest::Error),
+    /// Payload or response body isn't JSON of the expected shape
+    Decode(serde_json::Error),
+    /// Tencent Cloud answered with an `Error` object,
+    /// e.g. `AuthFailure.SignatureExpire` or `LimitExceeded`
+    Api {
+        code: String,
+        message: String,
+        request_id: Option<String>,
+    },
+    /// Public ip address couldn't be detected
+    IpDetection(String),
+    /// Detected public ip mustn't be written into firewall rules, e.g. it is a private
+    /// address or outside the expected prefixes
+    InvalidIp(String),
+    /// Local state file couldn't be read or written
+    StateIo(io::Error),
+    /// Firewall isn't in the state the operation expects
+    Conflict(String),
+    /// Required setting such as SECRETID is missing or invalid
+    Config(String),
+}
+
+impl QCloudError {
+    /// Tencent Cloud error code, if the error comes from the API
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            QCloudError::Api { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    /// `RequestId` of the failed API call, if any
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            QCloudError::Api { request_id, .. } => request_id.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+impl error::Error for QCloudError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            QCloudError::Transport(e) => Some(e),
+            QCloudError::Decode(e) => Some(e),
+            QCloudError::StateIo(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for QCloudError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QCloudError::Transport(e) => write!(f, "Request failed: {e}"),
+            QCloudError::Decode(e) => write!(f, "Invalid JSON: {e}"),
+            QCloudError::Api { message, .. } => write!(f, "{message}"),
+            QCloudError::IpDetection(msg) => write!(f, "Failed to detect public ip: {msg}"),
+            QCloudError::InvalidIp(msg) => write!(f, "Invalid public ip: {msg}"),
+            QCloudError::StateIo(e) => write!(f, "Failed to access state file: {e}"),
+            QCloudError::Conflict(msg) => write!(f, "{msg}"),
+            QCloudError::Config(msg) => write!(f, "Invalid configuration: {msg}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for QCloudError {
+    fn from(e: reqwest::Error) -> Self {
+        QCloudError::Transport(e)
+    }
+}
+
+impl From<serde_json::Error> for QCloudError {
+    fn from(e: serde_json::Error) -> Self {
+        QCloudError::Decode(e)
+    }
+}
+
+impl From<io::Error> for QCloudError {
+    fn from(e: io::Error) -> Self {
+        QCloudError::StateIo(e)
+    }
+}
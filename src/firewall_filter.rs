@@ -0,0 +1,110 @@
+use crate::{response::FirewallRuleSet, IpPrefix};
+
+/// Criteria to select live firewall rules. Unset criteria match every rule.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct FirewallRuleFilter {
+    /// Part of the description, case insensitive
+    pub description: Option<String>,
+    /// Protocol such as TCP, case insensitive
+    pub protocol: Option<String>,
+    /// Port exactly as configured, e.g. `443` or `8000-9000`
+    pub port: Option<String>,
+    /// Cidr block, a single address equals its host cidr
+    pub cidr: Option<String>,
+}
+
+impl FirewallRuleFilter {
+    /// Whether `rule` meets all criteria
+    pub fn matches(&self, rule: &FirewallRuleSet) -> bool {
+        let description = self.description.as_ref().is_none_or(|part| {
+            rule.firewall_rule_description
+                .as_deref()
+                .unwrap_or_default()
+                .to_lowercase()
+                .contains(&part.to_lowercase())
+        });
+        let protocol = self
+            .protocol
+            .as_ref()
+            .is_none_or(|protocol| protocol.eq_ignore_ascii_case(&rule.protocol));
+        let port = self
+            .port
+            .as_ref()
+            .is_none_or(|port| rule.port.as_deref() == Some(port.as_str()));
+        let cidr = self.cidr.as_ref().is_none_or(|cidr| {
+            same_cidr(cidr, &rule.cidr_block)
+                || rule
+                    .ipv6_cidr_block
+                    .as_deref()
+                    .is_some_and(|ipv6_cidr| same_cidr(cidr, ipv6_cidr))
+        });
+        description && protocol && port && cidr
+    }
+
+    /// Keep the rules meeting all criteria
+    pub fn apply(&self, rules: Vec<FirewallRuleSet>) -> Vec<FirewallRuleSet> {
+        rules
+            .into_iter()
+            .filter(|rule| self.matches(rule))
+            .collect()
+    }
+}
+
+/// Whether two cidr blocks are the same, treating a single address as its host cidr
+/// (`1.2.3.4` and `1.2.3.4/32`, `2400:3200::1` and `2400:3200::1/128`). Blocks which aren't
+/// valid cidr are compared as text
+pub(crate) fn same_cidr(a: &str, b: &str) -> bool {
+    match (a.parse::<IpPrefix>(), b.parse::<IpPrefix>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a.trim().eq_ignore_ascii_case(b.trim()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::live;
+
+    #[test]
+    fn test_empty_filter_matches_everything() {
+        let rule = live("https", "0.0.0.0/0");
+        assert!(FirewallRuleFilter::default().matches(&rule));
+    }
+
+    #[test]
+    fn test_filter_by_all_criteria() {
+        let rules = vec![
+            live("[iphone11] frp nas https", "1.2.3.4"),
+            FirewallRuleSet {
+                protocol: "udp".to_string(),
+                ..live("[iphone11] quic", "1.2.3.4")
+            },
+            FirewallRuleSet {
+                port: Some("22".to_string()),
+                ..live("[iphone11] ssh", "1.2.3.4")
+            },
+            live("public https", "0.0.0.0/0"),
+        ];
+        let filter = FirewallRuleFilter {
+            description: Some("IPHONE11".to_string()),
+            protocol: Some("TCP".to_string()),
+            port: Some("443".to_string()),
+            cidr: Some("1.2.3.4/32".to_string()),
+        };
+        let result = filter.apply(rules);
+        assert_eq!(result.len(), 1);
+        assert_eq!(
+            result[0].firewall_rule_description.as_deref(),
+            Some("[iphone11] frp nas https")
+        );
+    }
+
+    #[test]
+    fn test_same_cidr_strips_host_length_of_own_family_only() {
+        assert!(same_cidr("1.2.3.4", "1.2.3.4/32"));
+        assert!(same_cidr("2400:3200::1", "2400:3200:0::1/128"));
+        assert!(same_cidr("2400:3200::1", "2400:3200::1"));
+        assert!(!same_cidr("2400:3200::/32", "2400:3200::"));
+        assert!(!same_cidr("1.2.3.0/24", "1.2.3.0"));
+    }
+}
@@ -0,0 +1,428 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    firewall_filter::same_cidr,
+    request::{CreateDeleteFirewallRulesRequest, FirewallRule},
+    response::FirewallRuleSet,
+    IpFamily, PublicIps,
+};
+
+/// Changes that bring the live firewall in line with a payload template.
+///
+/// Only live rules sharing a description with the template are considered, everything else in
+/// the firewall is left alone. Template rules without description can't be told apart from
+/// rules the template doesn't manage, so live rules without description only count when they
+/// match such a rule exactly, and are never removed. Template rules with an `Ipv6CidrBlock`
+/// are IPv6 rules, all others IPv4 rules; rules of a family without public ip are left alone
+/// as well.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FirewallPlan {
+    pub instance_id: String,
+    /// Rules of the template missing in the firewall
+    pub to_add: Vec<FirewallRule>,
+    /// Live rules with a template description which the template doesn't produce
+    pub to_remove: Vec<FirewallRule>,
+    /// Rules already in the firewall as the template wants them
+    pub unchanged: Vec<FirewallRule>,
+}
+
+impl FirewallPlan {
+    /// Compare `tpl`, with its cidr replaced by the public ip of the rule family, against
+    /// `live` rules.
+    pub fn new(
+        instance_id: &str,
+        tpl: &CreateDeleteFirewallRulesRequest,
+        ips: &PublicIps,
+        live: &[FirewallRuleSet],
+    ) -> Self {
+        let desc_list = descriptions(tpl);
+        let desired = templated_rules(tpl, ips);
+        let live: Vec<&FirewallRuleSet> = live
+            .iter()
+            .filter(|item| {
+                let managed = match item.firewall_rule_description.as_deref() {
+                    Some(desc) if !desc.is_empty() => desc_list.iter().any(|d| d == desc),
+                    _ => tpl_matches(&desired, item),
+                };
+                managed && ips.get(live_family(item)).is_some()
+            })
+            .collect();
+
+        let (unchanged, to_add): (Vec<FirewallRule>, Vec<FirewallRule>) = desired
+            .into_iter()
+            .partition(|rule| live.iter().any(|item| rule_matches(rule, item)));
+        let to_remove = live
+            .iter()
+            .filter(|item| !tpl_matches(&unchanged, item))
+            .map(|item| firewall_rule_of(item))
+            .collect();
+
+        Self {
+            instance_id: instance_id.to_string(),
+            to_add,
+            to_remove,
+            unchanged,
+        }
+    }
+
+    /// Whether the firewall already matches the template
+    pub fn is_empty(&self) -> bool {
+        self.to_add.is_empty() && self.to_remove.is_empty()
+    }
+
+    /// Descriptions of all rules the plan touches or keeps
+    pub fn descriptions(&self) -> Vec<String> {
+        let mut desc_list: Vec<String> = Vec::new();
+        for rule in self
+            .to_add
+            .iter()
+            .chain(self.to_remove.iter())
+            .chain(self.unchanged.iter())
+        {
+            if let Some(desc) = &rule.firewall_rule_description {
+                if !desc_list.contains(desc) {
+                    desc_list.push(desc.clone());
+                }
+            }
+        }
+        desc_list
+    }
+}
+
+/// Human readable plan: one line per rule prefixed with `+` (add), `-` (remove) or `=`
+/// (unchanged), followed by a summary
+impl fmt::Display for FirewallPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Instance {}:", self.instance_id)?;
+        for (sign, rules) in [
+            ("+", &self.to_add),
+            ("-", &self.to_remove),
+            ("=", &self.unchanged),
+        ] {
+            for rule in rules.iter() {
+                writeln!(f, "  {sign} {}", RuleLine(rule))?;
+            }
+        }
+        if self.is_empty() {
+            writeln!(f, "No changes. Firewall matches the template.")
+        } else {
+            writeln!(
+                f,
+                "Plan: {} to add, {} to remove, {} unchanged.",
+                self.to_add.len(),
+                self.to_remove.len(),
+                self.unchanged.len()
+            )
+        }
+    }
+}
+
+/// Rule as shown in plans, e.g. `TCP 22 from 1.2.3.4 ACCEPT "ssh"`
+pub(crate) struct RuleLine<'a>(pub(crate) &'a FirewallRule);
+
+impl fmt::Display for RuleLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rule = self.0;
+        write!(
+            f,
+            "{} {} from {} {} \"{}\"",
+            rule.protocol.as_deref().unwrap_or("-"),
+            rule.port.as_deref().unwrap_or("ALL"),
+            rule.ipv6_cidr_block
+                .as_deref()
+                .or(rule.cidr_block.as_deref())
+                .unwrap_or("-"),
+            rule.action.as_deref().unwrap_or("ACCEPT"),
+            rule.firewall_rule_description
+                .as_deref()
+                .unwrap_or_default()
+        )
+    }
+}
+
+/// Cidr block written into exported templates. It is replaced by the public ip like any other
+/// cidr of a template.
+pub const CIDR_PLACEHOLDER: &str = "${PUBLIC_IP}";
+
+/// Build a payload template from live `rules`, with the cidr replaced by `CIDR_PLACEHOLDER`.
+/// Rules differing only in their cidr end up as one template rule.
+pub fn export_template(
+    instance_id: &str,
+    rules: &[FirewallRuleSet],
+) -> CreateDeleteFirewallRulesRequest {
+    let mut firewall_rules: Vec<FirewallRule> = Vec::new();
+    for rule in rules.iter() {
+        let rule = match live_family(rule) {
+            IpFamily::Ipv4 => FirewallRule {
+                cidr_block: Some(CIDR_PLACEHOLDER.to_string()),
+                ..firewall_rule_of(rule)
+            },
+            IpFamily::Ipv6 => FirewallRule {
+                ipv6_cidr_block: Some(CIDR_PLACEHOLDER.to_string()),
+                ..firewall_rule_of(rule)
+            },
+        };
+        if !firewall_rules.contains(&rule) {
+            firewall_rules.push(rule);
+        }
+    }
+    CreateDeleteFirewallRulesRequest {
+        instance_id: instance_id.to_string(),
+        firewall_rules,
+    }
+}
+
+/// Descriptions of the rules in `tpl`, leaving out empty ones
+pub fn descriptions(tpl: &CreateDeleteFirewallRulesRequest) -> Vec<String> {
+    tpl.firewall_rules
+        .iter()
+        .filter_map(|rule| rule.firewall_rule_description.clone())
+        .filter(|desc| !desc.is_empty())
+        .collect()
+}
+
+/// Rules of `tpl` with the cidr replaced by the public ip of their family, without duplicates.
+/// Rules of a family without public ip are dropped.
+pub(crate) fn templated_rules(
+    tpl: &CreateDeleteFirewallRulesRequest,
+    ips: &PublicIps,
+) -> Vec<FirewallRule> {
+    let mut rules: Vec<FirewallRule> = Vec::new();
+    for rule in tpl.firewall_rules.iter() {
+        let rule = match (rule_family(rule), ips.get(rule_family(rule))) {
+            (IpFamily::Ipv4, Some(ip)) => FirewallRule {
+                cidr_block: Some(ip.to_owned()),
+                ipv6_cidr_block: None,
+                ..rule.clone()
+            },
+            (IpFamily::Ipv6, Some(ip)) => FirewallRule {
+                cidr_block: None,
+                ipv6_cidr_block: Some(format!("{ip}/128")),
+                ..rule.clone()
+            },
+            (_, None) => continue,
+        };
+        if !rules.contains(&rule) {
+            rules.push(rule);
+        }
+    }
+    rules
+}
+
+/// Family of a template rule, IPv6 if it has an `Ipv6CidrBlock`
+pub(crate) fn rule_family(rule: &FirewallRule) -> IpFamily {
+    match rule.ipv6_cidr_block {
+        Some(_) => IpFamily::Ipv6,
+        None => IpFamily::Ipv4,
+    }
+}
+
+/// Family of a live rule, IPv6 if it has a non empty `Ipv6CidrBlock`
+pub(crate) fn live_family(rule: &FirewallRuleSet) -> IpFamily {
+    match rule.ipv6_cidr_block.as_deref() {
+        Some(cidr) if !cidr.is_empty() => IpFamily::Ipv6,
+        _ => IpFamily::Ipv4,
+    }
+}
+
+fn tpl_matches(rules: &[FirewallRule], live: &FirewallRuleSet) -> bool {
+    rules.iter().any(|rule| rule_matches(rule, live))
+}
+
+/// Convert a live rule into a rule of a request
+pub(crate) fn firewall_rule_of(rule: &FirewallRuleSet) -> FirewallRule {
+    let (cidr_block, ipv6_cidr_block) = match live_family(rule) {
+        IpFamily::Ipv4 => (Some(rule.cidr_block.clone()), None),
+        IpFamily::Ipv6 => (None, rule.ipv6_cidr_block.clone()),
+    };
+    FirewallRule {
+        protocol: Some(rule.protocol.clone()),
+        port: rule.port.clone(),
+        cidr_block,
+        ipv6_cidr_block,
+        action: rule.action.clone(),
+        firewall_rule_description: rule.firewall_rule_description.clone(),
+    }
+}
+
+/// Whether `live` is the rule `rule` describes. Protocol and action are case insensitive, a
+/// single address equals its host cidr (`1.2.3.4` and `1.2.3.4/32`) and a missing description
+/// equals an empty one.
+pub(crate) fn rule_matches(rule: &FirewallRule, live: &FirewallRuleSet) -> bool {
+    let eq_ignore_case = |a: &Option<String>, b: &Option<String>| match (a, b) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        (a, b) => a == b,
+    };
+    let description = |desc: &Option<String>| desc.clone().unwrap_or_default();
+    let cidr = match rule_family(rule) {
+        IpFamily::Ipv4 => rule
+            .cidr_block
+            .as_deref()
+            .is_some_and(|cidr| same_cidr(cidr, &live.cidr_block)),
+        IpFamily::Ipv6 => rule
+            .ipv6_cidr_block
+            .as_deref()
+            .zip(live.ipv6_cidr_block.as_deref())
+            .is_some_and(|(cidr, live_cidr)| same_cidr(cidr, live_cidr)),
+    };
+    eq_ignore_case(&rule.protocol, &Some(live.protocol.clone()))
+        && rule.port == live.port
+        && cidr
+        && eq_ignore_case(&rule.action, &live.action)
+        && description(&rule.firewall_rule_description)
+            == description(&live.firewall_rule_description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{live, tpl};
+
+    #[test]
+    fn test_plan_is_empty_when_firewall_matches() {
+        let plan = FirewallPlan::new(
+            "lhins-3jq1gki4",
+            &tpl(&["a", "b"]),
+            &PublicIps::from("1.1.1.1"),
+            &[live("a", "1.1.1.1/32"), live("b", "1.1.1.1")],
+        );
+        assert!(plan.is_empty());
+        assert_eq!(plan.unchanged.len(), 2);
+    }
+
+    #[test]
+    fn test_plan_adds_and_removes_only_differences() {
+        let plan = FirewallPlan::new(
+            "lhins-3jq1gki4",
+            &tpl(&["a", "b"]),
+            &PublicIps::from("2.2.2.2"),
+            &[
+                live("a", "2.2.2.2"),
+                live("b", "1.1.1.1"),
+                live("someone else", "3.3.3.3"),
+            ],
+        );
+        assert_eq!(plan.unchanged.len(), 1);
+        assert_eq!(plan.to_add.len(), 1);
+        assert_eq!(
+            plan.to_add[0].firewall_rule_description.as_deref(),
+            Some("b")
+        );
+        assert_eq!(plan.to_add[0].cidr_block.as_deref(), Some("2.2.2.2"));
+        assert_eq!(plan.to_remove.len(), 1);
+        assert_eq!(plan.to_remove[0].cidr_block.as_deref(), Some("1.1.1.1"));
+    }
+
+    #[test]
+    fn test_plan_deduplicates_template_rules() {
+        let plan = FirewallPlan::new(
+            "lhins-3jq1gki4",
+            &tpl(&["a", "a"]),
+            &PublicIps::from("2.2.2.2"),
+            &[live("a", "2.2.2.2")],
+        );
+        assert!(plan.is_empty());
+        assert_eq!(plan.unchanged.len(), 1);
+    }
+
+    #[test]
+    fn test_plan_templates_each_family_with_its_own_ip() {
+        let mut tpl = tpl(&["a", "b"]);
+        tpl.firewall_rules[1].cidr_block = None;
+        tpl.firewall_rules[1].ipv6_cidr_block = Some(CIDR_PLACEHOLDER.to_string());
+        let live_v6 = FirewallRuleSet {
+            cidr_block: String::new(),
+            ipv6_cidr_block: Some("2400:3200::1/128".to_string()),
+            ..live("b", "")
+        };
+
+        let ips = PublicIps {
+            ipv4: Some("2.2.2.2".to_string()),
+            ipv6: Some("2400:3200::2".to_string()),
+        };
+        let plan = FirewallPlan::new("lhins-3jq1gki4", &tpl, &ips, std::slice::from_ref(&live_v6));
+        assert_eq!(plan.to_add.len(), 2);
+        assert_eq!(plan.to_add[0].cidr_block.as_deref(), Some("2.2.2.2"));
+        assert_eq!(plan.to_add[1].cidr_block, None);
+        assert_eq!(
+            plan.to_add[1].ipv6_cidr_block.as_deref(),
+            Some("2400:3200::2/128")
+        );
+        assert_eq!(plan.to_remove.len(), 1);
+        assert_eq!(
+            plan.to_remove[0].ipv6_cidr_block.as_deref(),
+            Some("2400:3200::1/128")
+        );
+
+        // without IPv6 address, IPv6 rules are left alone
+        let plan = FirewallPlan::new(
+            "lhins-3jq1gki4",
+            &tpl,
+            &PublicIps::from("2.2.2.2"),
+            &[live_v6],
+        );
+        assert_eq!(plan.to_add.len(), 1);
+        assert!(plan.to_remove.is_empty());
+    }
+
+    #[test]
+    fn test_plan_display() {
+        let plan = FirewallPlan::new(
+            "lhins-3jq1gki4",
+            &tpl(&["a"]),
+            &PublicIps::from("2.2.2.2"),
+            &[live("a", "1.1.1.1")],
+        );
+        assert_eq!(
+            plan.to_string(),
+            r#"Instance lhins-3jq1gki4:
+  + TCP 443 from 2.2.2.2 ACCEPT "a"
+  - tcp 443 from 1.1.1.1 ACCEPT "a"
+Plan: 1 to add, 1 to remove, 0 unchanged.
+"#
+        );
+    }
+
+    #[test]
+    fn test_export_template_replaces_cidr_and_merges_rules() {
+        let tpl = export_template(
+            "lhins-3jq1gki4",
+            &[
+                live("a", "1.1.1.1"),
+                live("a", "2.2.2.2"),
+                live("b", "1.1.1.1"),
+            ],
+        );
+        assert_eq!(tpl.instance_id, "lhins-3jq1gki4");
+        assert_eq!(tpl.firewall_rules.len(), 2);
+        assert!(tpl
+            .firewall_rules
+            .iter()
+            .all(|rule| rule.cidr_block.as_deref() == Some(CIDR_PLACEHOLDER)));
+
+        // an exported template plans the same rules for a new ip
+        let plan = FirewallPlan::new("lhins-3jq1gki4", &tpl, &PublicIps::from("3.3.3.3"), &[]);
+        assert_eq!(plan.to_add.len(), 2);
+        assert_eq!(plan.to_add[0].cidr_block.as_deref(), Some("3.3.3.3"));
+    }
+
+    #[test]
+    fn test_plan_without_descriptions_is_idempotent() {
+        let mut tpl = tpl(&["a"]);
+        tpl.firewall_rules[0].firewall_rule_description = None;
+        let ips = PublicIps::from("2.2.2.2");
+        // someone else's rule without description
+        let mut rules = vec![live("", "3.3.3.3")];
+        let plan = FirewallPlan::new("lhins-3jq1gki4", &tpl, &ips, &rules);
+        assert_eq!(plan.to_add.len(), 1);
+        assert!(plan.to_remove.is_empty());
+
+        // the firewall reports the created rule with an empty description
+        rules.push(live("", plan.to_add[0].cidr_block.as_deref().unwrap()));
+        let plan = FirewallPlan::new("lhins-3jq1gki4", &tpl, &ips, &rules);
+        assert!(plan.is_empty(), "{plan}");
+        assert_eq!(plan.unchanged.len(), 1);
+    }
+}
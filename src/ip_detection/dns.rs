@@ -0,0 +1,246 @@
+//! "What is my ip" DNS names answered by the authoritative servers of OpenDNS, Google and
+//! Akamai with the address of the querying host
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::{lookup_host, UdpSocket};
+
+use super::IpFamily;
+use crate::QCloudError;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const CLASS_IN: u16 = 1;
+
+/// Record type holding the ip in the answer
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsRecord {
+    #[default]
+    A,
+    Aaaa,
+    Txt,
+}
+
+impl DnsRecord {
+    fn code(self) -> u16 {
+        match self {
+            DnsRecord::A => 1,
+            DnsRecord::Aaaa => 28,
+            DnsRecord::Txt => 16,
+        }
+    }
+}
+
+fn dns_error(message: &str) -> QCloudError {
+    QCloudError::IpDetection(format!("DNS {message}"))
+}
+
+fn socket_error(e: std::io::Error) -> QCloudError {
+    dns_error(&format!("socket failed: {e}"))
+}
+
+/// Ask `resolver` (`host:port`) over `family` for the `record` of `name` and return the ip in
+/// the answer. An A record is asked as AAAA record for IPv6.
+pub(super) async fn detect(
+    resolver: &str,
+    name: &str,
+    record: DnsRecord,
+    family: IpFamily,
+) -> Result<String, QCloudError> {
+    let record = match (record, family) {
+        (DnsRecord::A, IpFamily::Ipv6) => DnsRecord::Aaaa,
+        (record, _) => record,
+    };
+    let server = lookup_host(resolver)
+        .await
+        .map_err(socket_error)?
+        .find(|addr| family.contains(addr))
+        .ok_or_else(|| dns_error(&format!("resolver {resolver} has no {family} address")))?;
+    let bind_addr = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_addr).await.map_err(socket_error)?;
+    socket.connect(server).await.map_err(socket_error)?;
+
+    let id: u16 = rand::random();
+    socket
+        .send(&query(id, name, record)?)
+        .await
+        .map_err(socket_error)?;
+    let mut buf = [0u8; 1500];
+    let len = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| dns_error(&format!("query to {resolver} timed out")))?
+        .map_err(socket_error)?;
+    parse_answer(&buf[..len], id, record)
+}
+
+/// Recursion desired query for a single question
+fn query(id: u16, name: &str, record: DnsRecord) -> Result<Vec<u8>, QCloudError> {
+    let mut packet = Vec::with_capacity(512);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // flags: standard query, recursion desired; 1 question
+    packet.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(dns_error(&format!("invalid name {name}")));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&record.code().to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Result<u16, QCloudError> {
+    packet
+        .get(pos..pos + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| dns_error("answer is truncated"))
+}
+
+/// Position after the (possibly compressed) name starting at `pos`
+fn skip_name(packet: &[u8], mut pos: usize) -> Result<usize, QCloudError> {
+    loop {
+        let len = *packet
+            .get(pos)
+            .ok_or_else(|| dns_error("answer is truncated"))?;
+        match len {
+            0 => return Ok(pos + 1),
+            // a pointer ends the name
+            len if len & 0xC0 == 0xC0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+/// First ip of type `record` in the answer section of the response `packet` to query `id`
+fn parse_answer(packet: &[u8], id: u16, record: DnsRecord) -> Result<String, QCloudError> {
+    if read_u16(packet, 0)? != id {
+        return Err(dns_error("answer doesn't match the query"));
+    }
+    let rcode = read_u16(packet, 2)? & 0x000F;
+    if rcode != 0 {
+        return Err(dns_error(&format!("query failed with rcode {rcode}")));
+    }
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos)? + 4;
+    }
+    for _ in 0..answers {
+        pos = skip_name(packet, pos)?;
+        let rtype = read_u16(packet, pos)?;
+        let rdlength = read_u16(packet, pos + 8)? as usize;
+        pos += 10;
+        let rdata = packet
+            .get(pos..pos + rdlength)
+            .ok_or_else(|| dns_error("answer is truncated"))?;
+        pos += rdlength;
+        if rtype != record.code() {
+            continue;
+        }
+        match record {
+            DnsRecord::A if rdata.len() == 4 => {
+                return Ok(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).to_string());
+            }
+            DnsRecord::Aaaa if rdata.len() == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                return Ok(Ipv6Addr::from(octets).to_string());
+            }
+            DnsRecord::Txt => {
+                // Google also answers with a TXT record about the EDNS client subnet
+                let text = txt_strings(rdata);
+                if text.parse::<std::net::IpAddr>().is_ok() {
+                    return Ok(text);
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(dns_error(&format!("answer has no {record:?} record")))
+}
+
+/// Character strings of a TXT record joined together
+fn txt_strings(rdata: &[u8]) -> String {
+    let mut text = String::new();
+    let mut pos = 0;
+    while let Some(&len) = rdata.get(pos) {
+        let end = (pos + 1 + len as usize).min(rdata.len());
+        text.push_str(&String::from_utf8_lossy(&rdata[pos + 1..end]));
+        pos = end;
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answer the first query received on a local port with `record` holding `rdata`
+    async fn stand_in_resolver(record: DnsRecord, rdatas: Vec<Vec<u8>>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let mut answer = buf[..len].to_vec();
+            // response flags and answer count
+            answer[2] = 0x81;
+            answer[3] = 0x80;
+            answer[7] = rdatas.len() as u8;
+            for rdata in rdatas {
+                // name as pointer to the question
+                answer.extend_from_slice(&[0xC0, 0x0C]);
+                answer.extend_from_slice(&record.code().to_be_bytes());
+                answer.extend_from_slice(&CLASS_IN.to_be_bytes());
+                answer.extend_from_slice(&60u32.to_be_bytes());
+                answer.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                answer.extend_from_slice(&rdata);
+            }
+            socket.send_to(&answer, peer).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_detect_reads_a_record() {
+        let resolver = stand_in_resolver(DnsRecord::A, vec![vec![203, 0, 113, 7]]).await;
+        let ip = detect(&resolver, "myip.opendns.com", DnsRecord::A, IpFamily::Ipv4)
+            .await
+            .unwrap();
+        assert_eq!(ip, "203.0.113.7");
+    }
+
+    #[tokio::test]
+    async fn test_detect_skips_txt_records_without_ip() {
+        let resolver = stand_in_resolver(
+            DnsRecord::Txt,
+            vec![
+                b"\x1Bedns0-client-subnet 1.2.3.0/24".to_vec(),
+                b"\x0B203.0.113.7".to_vec(),
+            ],
+        )
+        .await;
+        let ip = detect(
+            &resolver,
+            "o-o.myaddr.l.google.com",
+            DnsRecord::Txt,
+            IpFamily::Ipv4,
+        )
+        .await
+        .unwrap();
+        assert_eq!(ip, "203.0.113.7");
+    }
+}
@@ -0,0 +1,62 @@
+//! Ip echo services over HTTP
+
+use serde_json::Value;
+
+use crate::QCloudError;
+
+/// Get `url` and extract the ip from the field at `json_path`, or from the whole body
+pub(super) async fn detect(
+    client: &reqwest::Client,
+    url: &str,
+    json_path: Option<&str>,
+) -> Result<String, QCloudError> {
+    let body = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| QCloudError::IpDetection(e.to_string()))?
+        .text()
+        .await
+        .map_err(|e| QCloudError::IpDetection(e.to_string()))?;
+    let ip = match json_path {
+        Some(json_path) => {
+            let root: Value = serde_json::from_str(&body)?;
+            lookup(&root, json_path)
+                .and_then(Value::as_str)
+                .ok_or_else(|| QCloudError::IpDetection(format!("no {json_path} in answer")))?
+                .to_string()
+        }
+        None => body,
+    };
+    let ip = ip.trim();
+    if ip.is_empty() {
+        return Err(QCloudError::IpDetection("empty answer".to_string()));
+    }
+    Ok(ip.to_string())
+}
+
+/// Value at a dot separated path of keys and array indices, e.g. `results.0.ip`
+fn lookup<'a>(value: &'a Value, json_path: &str) -> Option<&'a Value> {
+    json_path
+        .split('.')
+        .try_fold(value, |value, key| match value {
+            Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+            _ => value.get(key),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_follows_keys_and_indices() {
+        let root: Value = serde_json::from_str(r#"{"results":[{"ip":"1.2.3.4"}]}"#).unwrap();
+        assert_eq!(
+            lookup(&root, "results.0.ip").and_then(Value::as_str),
+            Some("1.2.3.4")
+        );
+        assert!(lookup(&root, "results.1.ip").is_none());
+    }
+}
@@ -0,0 +1,83 @@
+//! Addresses of a local network interface, for hosts holding the public ip themselves, e.g.
+//! the pppoe-wan interface of an OpenWrt router
+
+use std::{
+    ffi::CStr,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use super::{is_public_ip, IpFamily};
+use crate::QCloudError;
+
+fn interface_error(message: &str) -> QCloudError {
+    QCloudError::IpDetection(format!("interface {message}"))
+}
+
+/// First public address of `family` of the interface called `name`
+pub(super) fn detect(name: &str, family: IpFamily) -> Result<String, QCloudError> {
+    let addresses = interface_addresses(name)
+        .map_err(|e| interface_error(&format!("addresses can't be read: {e}")))?;
+    if addresses.is_empty() {
+        return Err(interface_error(&format!("{name} has no address")));
+    }
+    addresses
+        .into_iter()
+        .find(|ip| IpFamily::of(ip) == family && is_public_ip(ip))
+        .map(|ip| ip.to_string())
+        .ok_or_else(|| interface_error(&format!("{name} has no public {family} address")))
+}
+
+/// All IPv4 and IPv6 addresses of the interface called `name`, by getifaddrs
+fn interface_addresses(name: &str) -> io::Result<Vec<IpAddr>> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addresses = Vec::new();
+    let mut cursor = ifaddrs;
+    while !cursor.is_null() {
+        // the list stays valid until freeifaddrs
+        let ifaddr = unsafe { &*cursor };
+        cursor = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null() || ifaddr.ifa_name.is_null() {
+            continue;
+        }
+        if unsafe { CStr::from_ptr(ifaddr.ifa_name) }.to_bytes() != name.as_bytes() {
+            continue;
+        }
+        match i32::from(unsafe { (*ifaddr.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                addresses.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    addr.sin_addr.s_addr,
+                ))));
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                addresses.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_loopback_has_no_public_address() {
+        let addresses = interface_addresses("lo").unwrap();
+        assert!(addresses.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert!(detect("lo", IpFamily::Ipv4)
+            .unwrap_err()
+            .to_string()
+            .contains("lo has no public IPv4 address"));
+        assert!(detect("no-such-interface0", IpFamily::Ipv6).is_err());
+    }
+}
@@ -0,0 +1,717 @@
+//! Public ip detection from several independent providers
+
+mod dns;
+mod http;
+mod interface;
+mod natpmp;
+mod prefix;
+mod stun;
+mod upnp;
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::QCloudError;
+
+pub use dns::DnsRecord;
+pub use prefix::IpPrefix;
+
+lazy_static! {
+    // shared by all providers, a provider that hangs must not stall the detection. Binding
+    // the unspecified address of a family makes echo services see the address of that family
+    static ref DETECTION_CLIENT_V4: reqwest::Client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        .build()
+        .unwrap_or_default();
+    static ref DETECTION_CLIENT_V6: reqwest::Client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        .build()
+        .unwrap_or_default();
+}
+
+/// Address family, detected and allowed in the firewall independently of the other
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    #[default]
+    Ipv4,
+    Ipv6,
+}
+
+impl IpFamily {
+    /// Family of `ip`
+    pub fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => IpFamily::Ipv4,
+            IpAddr::V6(_) => IpFamily::Ipv6,
+        }
+    }
+
+    /// Whether `addr` belongs to this family
+    pub fn contains(self, addr: &SocketAddr) -> bool {
+        IpFamily::of(&addr.ip()) == self
+    }
+
+    fn client(self) -> &'static reqwest::Client {
+        match self {
+            IpFamily::Ipv4 => &DETECTION_CLIENT_V4,
+            IpFamily::Ipv6 => &DETECTION_CLIENT_V6,
+        }
+    }
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpFamily::Ipv4 => write!(f, "IPv4"),
+            IpFamily::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Where a candidate public ip comes from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum IpProvider {
+    /// Echo service answering with the ip of the caller. The ip is taken from the field at
+    /// `json_path` (dot separated keys and array indices, e.g. `data.ip`) of a JSON body, or
+    /// from the whole body if `json_path` is unset
+    Http {
+        url: String,
+        json_path: Option<String>,
+    },
+    /// Special DNS name answered with the address of the querying host by its authoritative
+    /// `resolver` (`host:port`)
+    Dns {
+        resolver: String,
+        name: String,
+        #[serde(default)]
+        record: DnsRecord,
+    },
+    /// STUN server (`host:port`) answering binding requests with the reflexive address
+    Stun { server: String },
+    /// WAN address of the UPnP Internet Gateway Device with the device description at
+    /// `location`, or of the first one answering SSDP discovery if unset
+    Upnp { location: Option<String> },
+    /// WAN address of `gateway` (`ip` or `ip:port`) by NAT-PMP or PCP, the default gateway if
+    /// unset
+    NatPmp { gateway: Option<String> },
+    /// Public address of the local network interface called `name`, e.g. `pppoe-wan`
+    Interface { name: String },
+}
+
+impl IpProvider {
+    /// Echo service at `url` answering with plain text
+    pub fn http(url: &str) -> Self {
+        IpProvider::Http {
+            url: url.to_string(),
+            json_path: None,
+        }
+    }
+
+    /// Echo service at `url` answering with JSON, the ip being at `json_path`
+    pub fn http_json(url: &str, json_path: &str) -> Self {
+        IpProvider::Http {
+            url: url.to_string(),
+            json_path: Some(json_path.to_string()),
+        }
+    }
+
+    /// Ask `resolver` (`host:port`) for the `record` of `name`
+    pub fn dns(resolver: &str, name: &str, record: DnsRecord) -> Self {
+        IpProvider::Dns {
+            resolver: resolver.to_string(),
+            name: name.to_string(),
+            record,
+        }
+    }
+
+    /// `myip.opendns.com` at resolver1.opendns.com
+    pub fn opendns() -> Self {
+        Self::dns("resolver1.opendns.com:53", "myip.opendns.com", DnsRecord::A)
+    }
+
+    /// `o-o.myaddr.l.google.com` TXT at ns1.google.com
+    pub fn google_dns() -> Self {
+        Self::dns(
+            "ns1.google.com:53",
+            "o-o.myaddr.l.google.com",
+            DnsRecord::Txt,
+        )
+    }
+
+    /// `whoami.akamai.net` at ns1-1.akamaitech.net
+    pub fn akamai_dns() -> Self {
+        Self::dns("ns1-1.akamaitech.net:53", "whoami.akamai.net", DnsRecord::A)
+    }
+
+    /// Send binding requests to the STUN `server` (`host:port`)
+    pub fn stun(server: &str) -> Self {
+        IpProvider::Stun {
+            server: server.to_string(),
+        }
+    }
+
+    /// Ask the UPnP gateway found by SSDP discovery
+    pub fn upnp() -> Self {
+        IpProvider::Upnp { location: None }
+    }
+
+    /// Ask the default gateway by NAT-PMP or PCP
+    pub fn nat_pmp() -> Self {
+        IpProvider::NatPmp { gateway: None }
+    }
+
+    /// Read the address of the local network interface called `name`
+    pub fn interface(name: &str) -> Self {
+        IpProvider::Interface {
+            name: name.to_string(),
+        }
+    }
+
+    /// setb.cn, ifconfig.co, ipify and ip.sb
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::http_json("https://setb.cn/ip.json", "publicip"),
+            Self::http_json("https://ifconfig.co/json", "ip"),
+            Self::http("https://api.ipify.org"),
+            Self::http("https://api.ip.sb/ip"),
+        ]
+    }
+
+    /// Name to report the provider by
+    pub fn name(&self) -> String {
+        match self {
+            IpProvider::Http { url, .. } => url.clone(),
+            IpProvider::Dns { resolver, name, .. } => format!("{name}@{resolver}"),
+            IpProvider::Stun { server } => format!("stun:{server}"),
+            IpProvider::Upnp { location } => {
+                format!("upnp:{}", location.as_deref().unwrap_or("discovered"))
+            }
+            IpProvider::NatPmp { gateway } => {
+                format!(
+                    "nat-pmp:{}",
+                    gateway.as_deref().unwrap_or("default gateway")
+                )
+            }
+            IpProvider::Interface { name } => format!("interface:{name}"),
+        }
+    }
+
+    /// Ask the provider for the public ip of `family`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the provider can't be reached, its answer
+    /// doesn't contain an ip or the ip is of the other family.
+    pub async fn detect(&self, family: IpFamily) -> Result<String, QCloudError> {
+        let answer = match self {
+            IpProvider::Http { url, json_path } => {
+                http::detect(family.client(), url, json_path.as_deref()).await
+            }
+            IpProvider::Dns {
+                resolver,
+                name,
+                record,
+            } => dns::detect(resolver, name, *record, family).await,
+            IpProvider::Stun { server } => stun::detect(server, family).await,
+            IpProvider::Upnp { location } => match family {
+                IpFamily::Ipv4 => upnp::detect(family.client(), location.as_deref()).await,
+                IpFamily::Ipv6 => Err(only_ipv4()),
+            },
+            IpProvider::NatPmp { gateway } => match family {
+                IpFamily::Ipv4 => natpmp::detect(gateway.as_deref()).await,
+                IpFamily::Ipv6 => Err(only_ipv4()),
+            },
+            IpProvider::Interface { name } => interface::detect(name, family),
+        }?;
+        match answer.parse::<IpAddr>() {
+            Ok(ip) if IpFamily::of(&ip) == family => Ok(ip.to_string()),
+            Ok(ip) => Err(QCloudError::IpDetection(format!(
+                "answered {ip}, which is not {family}"
+            ))),
+            Err(_) => Err(QCloudError::IpDetection(format!(
+                "answered {answer:?}, which is not an ip"
+            ))),
+        }
+    }
+}
+
+fn only_ipv4() -> QCloudError {
+    QCloudError::IpDetection("gateways only report their IPv4 address".to_string())
+}
+
+/// Whether `ip` can be reached from the internet, i.e. it is none of the unspecified,
+/// loopback, private, CGNAT (100.64.0.0/10), link-local, multicast, broadcast, benchmarking
+/// or documentation addresses, nor an IPv6 unique local address
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // 0.0.0.0/8 "this network"
+                || a == 0
+                // 100.64.0.0/10 shared address space of carrier grade NAT
+                || (a == 100 && (b & 0xC0) == 64)
+                // 198.18.0.0/15 benchmarking
+                || (a == 198 && (b & 0xFE) == 18)
+                // 192.0.0.0/24 IETF protocol assignments
+                || (a == 192 && b == 0 && c == 0)
+                // 240.0.0.0/4 reserved
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            match ip.to_ipv4_mapped() {
+                Some(ip) => is_public_ip(&IpAddr::V4(ip)),
+                None => {
+                    !(ip.is_unspecified()
+                        || ip.is_loopback()
+                        || ip.is_multicast()
+                        // fc00::/7 unique local
+                        || (segments[0] & 0xFE00) == 0xFC00
+                        // fe80::/10 link-local
+                        || (segments[0] & 0xFFC0) == 0xFE80
+                        // 2001:db8::/32 documentation
+                        || (segments[0] == 0x2001 && segments[1] == 0x0DB8))
+                }
+            }
+        }
+    }
+}
+
+/// Public ip accepted by the quorum of providers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpDetection {
+    pub ip: String,
+    /// Names of the providers which reported `ip`
+    pub providers: Vec<String>,
+}
+
+/// Public addresses to allow in the firewall, per family. Rules of a family without address
+/// are left alone.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicIps {
+    pub ipv4: Option<String>,
+    pub ipv6: Option<String>,
+}
+
+impl PublicIps {
+    /// Addresses of `detections`, the first one of each family
+    pub fn from_detections(detections: &[IpDetection]) -> Self {
+        let mut ips = Self::default();
+        for detection in detections {
+            let slot = match detection.ip.parse::<IpAddr>().map(|ip| IpFamily::of(&ip)) {
+                Ok(IpFamily::Ipv6) => &mut ips.ipv6,
+                _ => &mut ips.ipv4,
+            };
+            if slot.is_none() {
+                *slot = Some(detection.ip.clone());
+            }
+        }
+        ips
+    }
+
+    /// Address of `family`
+    pub fn get(&self, family: IpFamily) -> Option<&str> {
+        match family {
+            IpFamily::Ipv4 => self.ipv4.as_deref(),
+            IpFamily::Ipv6 => self.ipv6.as_deref(),
+        }
+    }
+}
+
+/// A single address, IPv6 if it parses as such and IPv4 otherwise
+impl From<&str> for PublicIps {
+    fn from(ip: &str) -> Self {
+        match ip.trim().parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => Self {
+                ipv4: None,
+                ipv6: Some(ip.to_string()),
+            },
+            _ => Self {
+                ipv4: Some(ip.to_string()),
+                ipv6: None,
+            },
+        }
+    }
+}
+
+/// e.g. `1.2.3.4, 2400:3200::1`
+impl fmt::Display for PublicIps {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ips: Vec<&str> = [self.ipv4.as_deref(), self.ipv6.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect();
+        write!(f, "{}", ips.join(", "))
+    }
+}
+
+/// Queries all providers in parallel and accepts an ip once `quorum` of them agree on it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpDetector {
+    /// Providers asked one after another before `providers`, e.g. the router. The first
+    /// answer is trusted as is; `providers` are only asked if all of them fail
+    #[serde(default)]
+    pub preferred: Vec<IpProvider>,
+    #[serde(default = "IpProvider::defaults")]
+    pub providers: Vec<IpProvider>,
+    /// Number of providers that must report the same ip, 2 if unset (or 1 with a single
+    /// provider)
+    pub quorum: Option<usize>,
+    /// Families detected independently of each other, IPv4 only if unset
+    #[serde(default = "default_families")]
+    pub families: Vec<IpFamily>,
+    /// Prefixes the public ip is expected in, e.g. those of the ISP. Any public ip of a family
+    /// is accepted if no prefix of that family is listed
+    #[serde(default)]
+    pub allow: Vec<IpPrefix>,
+    /// Prefixes the public ip must not be in
+    #[serde(default)]
+    pub deny: Vec<IpPrefix>,
+}
+
+fn default_families() -> Vec<IpFamily> {
+    vec![IpFamily::Ipv4]
+}
+
+impl Default for IpDetector {
+    fn default() -> Self {
+        Self::new(IpProvider::defaults(), None)
+    }
+}
+
+impl IpDetector {
+    pub fn new(providers: Vec<IpProvider>, quorum: Option<usize>) -> Self {
+        Self {
+            preferred: Vec::new(),
+            providers,
+            quorum,
+            families: default_families(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+
+    /// Check that `ip` may be written into firewall rules: it is a public address, in one of
+    /// the `allow` prefixes if any and in none of the `deny` prefixes
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `ip` isn't an ip address or fails one of the
+    /// checks.
+    pub fn validate(&self, ip: &str) -> Result<IpAddr, QCloudError> {
+        let addr: IpAddr = ip
+            .trim()
+            .parse()
+            .map_err(|_| QCloudError::InvalidIp(format!("{ip:?} is not an ip address")))?;
+        if !is_public_ip(&addr) {
+            return Err(QCloudError::InvalidIp(format!(
+                "{addr} is not reachable from the internet (private, loopback, CGNAT, \
+                 multicast or documentation address)"
+            )));
+        }
+        if let Some(prefix) = self.deny.iter().find(|prefix| prefix.contains(&addr)) {
+            return Err(QCloudError::InvalidIp(format!(
+                "{addr} is in the denied prefix {prefix}"
+            )));
+        }
+        let family = IpFamily::of(&addr);
+        let allowed: Vec<&IpPrefix> = self
+            .allow
+            .iter()
+            .filter(|prefix| prefix.family() == family)
+            .collect();
+        if !allowed.is_empty() && !allowed.iter().any(|prefix| prefix.contains(&addr)) {
+            let allowed: Vec<String> = allowed.iter().map(|prefix| prefix.to_string()).collect();
+            return Err(QCloudError::InvalidIp(format!(
+                "{addr} is outside the allowed prefixes {}",
+                allowed.join(", ")
+            )));
+        }
+        Ok(addr)
+    }
+
+    fn quorum(&self) -> usize {
+        self.quorum
+            .unwrap_or(2)
+            .clamp(1, self.providers.len().max(1))
+    }
+
+    /// Detect the public ip of each family. A family which can't be detected is reported
+    /// and left out
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no family can be detected.
+    pub async fn detect_all(&self) -> Result<Vec<IpDetection>, QCloudError> {
+        let results = join_all(self.families.iter().map(|family| self.detect(*family))).await;
+        let mut detections = Vec::new();
+        let mut errors = Vec::new();
+        for (family, result) in self.families.iter().zip(results) {
+            match result {
+                Ok(detection) => detections.push(detection),
+                Err(err) if self.families.len() > 1 => {
+                    eprintln!("{family} is left out: {err}");
+                    errors.push(err);
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+        if detections.is_empty() {
+            return Err(errors
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| QCloudError::IpDetection("no family to detect".to_string())));
+        }
+        Ok(detections)
+    }
+
+    /// Ask the preferred providers, then all providers and return the ip of `family` reported
+    /// by most of them
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no preferred provider answers and no ip is
+    /// reported by at least `quorum` providers, or the ip fails [`IpDetector::validate`].
+    /// The error lists what each provider answered.
+    pub async fn detect(&self, family: IpFamily) -> Result<IpDetection, QCloudError> {
+        for provider in self.preferred.iter() {
+            match provider
+                .detect(family)
+                .await
+                .and_then(|ip| self.validate(&ip).map(|_| ip))
+            {
+                Ok(ip) => {
+                    return Ok(IpDetection {
+                        ip,
+                        providers: vec![provider.name()],
+                    })
+                }
+                Err(err) => eprintln!("{} failed, falling back: {err}", provider.name()),
+            }
+        }
+        let answers = join_all(
+            self.providers
+                .iter()
+                .map(|provider| provider.detect(family)),
+        )
+        .await;
+        let answers: Vec<(String, Result<String, QCloudError>)> = self
+            .providers
+            .iter()
+            .map(IpProvider::name)
+            .zip(answers)
+            .collect();
+        let detection = quorum_of(answers, self.quorum())?;
+        self.validate(&detection.ip)?;
+        Ok(detection)
+    }
+}
+
+/// Pick the ip reported by most providers if at least `quorum` agree on it
+fn quorum_of(
+    answers: Vec<(String, Result<String, QCloudError>)>,
+    quorum: usize,
+) -> Result<IpDetection, QCloudError> {
+    let mut candidates: Vec<IpDetection> = Vec::new();
+    let mut failures = Vec::new();
+    for (name, answer) in answers {
+        match answer {
+            Ok(ip) => match candidates.iter_mut().find(|candidate| candidate.ip == ip) {
+                Some(candidate) => candidate.providers.push(name),
+                None => candidates.push(IpDetection {
+                    ip,
+                    providers: vec![name],
+                }),
+            },
+            Err(QCloudError::IpDetection(message)) => {
+                failures.push(format!("{name} failed: {message}"))
+            }
+            Err(err) => failures.push(format!("{name} failed: {err}")),
+        }
+    }
+
+    // on a tie the first provider in the list wins
+    let best = candidates
+        .iter()
+        .enumerate()
+        .max_by_key(|(i, candidate)| (candidate.providers.len(), std::cmp::Reverse(*i)))
+        .map(|(i, _)| i);
+    match best {
+        Some(i) if candidates[i].providers.len() >= quorum => Ok(candidates.swap_remove(i)),
+        _ => {
+            let answers: Vec<String> = candidates
+                .iter()
+                .map(|candidate| {
+                    format!("{} from {}", candidate.ip, candidate.providers.join(", "))
+                })
+                .chain(failures)
+                .collect();
+            Err(QCloudError::IpDetection(format!(
+                "less than {quorum} provider(s) agree on an ip ({})",
+                answers.join("; ")
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::stand_in_server;
+
+    fn answer(name: &str, ip: &str) -> (String, Result<String, QCloudError>) {
+        (name.to_string(), Ok(ip.to_string()))
+    }
+
+    #[test]
+    fn test_is_public_ip() {
+        for ip in ["1.1.1.1", "203.1.113.7", "100.128.0.1", "2400:3200::1"] {
+            assert!(is_public_ip(&ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "224.0.0.1",
+            "203.0.113.7",
+            "fe80::1",
+            "fd00::1",
+            "::1",
+            "2001:db8::1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(!is_public_ip(&ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_quorum_accepts_ip_most_providers_agree_on() {
+        let answers = vec![
+            answer("a", "1.1.1.1"),
+            answer("b", "2.2.2.2"),
+            answer("c", "2.2.2.2"),
+            (
+                "d".to_string(),
+                Err(QCloudError::IpDetection("down".to_string())),
+            ),
+        ];
+        let detection = quorum_of(answers, 2).unwrap();
+        assert_eq!(detection.ip, "2.2.2.2");
+        assert_eq!(detection.providers, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_quorum_rejects_disagreeing_providers() {
+        let answers = vec![answer("a", "1.1.1.1"), answer("b", "2.2.2.2")];
+        let err = quorum_of(answers, 2).unwrap_err();
+        assert!(matches!(err, QCloudError::IpDetection(_)));
+        assert!(err.to_string().contains("1.1.1.1 from a"));
+    }
+
+    #[tokio::test]
+    async fn test_detector_queries_json_and_plain_text_providers() {
+        let (json_url, _) = stand_in_server(vec![r#"{"data":{"ip":"9.9.9.9"}}"#.into()]).await;
+        let (text_url, _) = stand_in_server(vec!["9.9.9.9\n".into()]).await;
+        let detector = IpDetector::new(
+            vec![
+                IpProvider::http_json(&json_url, "data.ip"),
+                IpProvider::http(&text_url),
+            ],
+            Some(2),
+        );
+        let detection = detector.detect(IpFamily::Ipv4).await.unwrap();
+        assert_eq!(detection.ip, "9.9.9.9");
+        assert_eq!(detection.providers, vec![json_url, text_url]);
+    }
+
+    #[tokio::test]
+    async fn test_detector_falls_back_from_unavailable_gateway() {
+        let (url, _) = stand_in_server(vec!["9.9.9.9".into()]).await;
+        let mut detector = IpDetector::new(vec![IpProvider::http(&url)], None);
+        // nothing listens on the discard port
+        detector.preferred = vec![IpProvider::Upnp {
+            location: Some("http://127.0.0.1:9/rootDesc.xml".to_string()),
+        }];
+        let detection = detector.detect(IpFamily::Ipv4).await.unwrap();
+        assert_eq!(detection.ip, "9.9.9.9");
+        assert_eq!(detection.providers, vec![url]);
+    }
+
+    #[test]
+    fn test_validate_checks_allowed_and_denied_prefixes() {
+        let mut detector = IpDetector::default();
+        assert!(detector.validate("9.9.9.9").is_ok());
+        for ip in [
+            "<html>Bad Gateway</html>",
+            "",
+            "10.1.2.3",
+            "100.64.0.1",
+            "127.0.0.1",
+        ] {
+            assert!(
+                matches!(detector.validate(ip), Err(QCloudError::InvalidIp(_))),
+                "{ip}"
+            );
+        }
+
+        detector.allow = vec!["9.9.0.0/16".parse().unwrap()];
+        detector.deny = vec!["9.9.9.0/24".parse().unwrap()];
+        assert!(detector.validate("9.9.8.8").is_ok());
+        assert!(matches!(
+            detector.validate("9.9.9.9"),
+            Err(QCloudError::InvalidIp(_))
+        ));
+        assert!(matches!(
+            detector.validate("8.8.8.8"),
+            Err(QCloudError::InvalidIp(_))
+        ));
+        // the allowed prefixes only restrict their own family
+        assert!(detector.validate("2400:3200::1").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_detector_rejects_private_ip() {
+        let (url, _) = stand_in_server(vec!["192.168.1.2".into()]).await;
+        let detector = IpDetector::new(vec![IpProvider::http(&url)], None);
+        assert!(matches!(
+            detector.detect(IpFamily::Ipv4).await,
+            Err(QCloudError::InvalidIp(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_family_failing_leaves_the_other_detected() {
+        // the echo service only ever answers with an IPv4 address
+        let (url, _) = stand_in_server(vec!["9.9.9.9".into(), "9.9.9.9".into()]).await;
+        let mut detector = IpDetector::new(vec![IpProvider::http(&url)], None);
+        detector.families = vec![IpFamily::Ipv4, IpFamily::Ipv6];
+        let detections = detector.detect_all().await.unwrap();
+        assert_eq!(detections.len(), 1);
+        assert_eq!(detections[0].ip, "9.9.9.9");
+
+        let (url, _) = stand_in_server(vec!["9.9.9.9".into()]).await;
+        let mut detector = IpDetector::new(vec![IpProvider::http(&url)], None);
+        detector.families = vec![IpFamily::Ipv6];
+        assert!(detector.detect_all().await.is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! External address of the gateway by NAT-PMP (RFC 6886), or PCP (RFC 6887) for gateways
+//! that only speak the newer protocol
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+use crate::QCloudError;
+
+const NAT_PMP_PORT: u16 = 5351;
+const ATTEMPTS: u32 = 3;
+const ATTEMPT_TIMEOUT: Duration = Duration::from_millis(750);
+/// Result code of a NAT-PMP answer from a gateway that doesn't speak version 0
+const UNSUPPORTED_VERSION: u16 = 1;
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+const PCP_RESPONSE: u8 = 0x80;
+/// Lifetime of the throwaway mapping PCP reports the external address with
+const PCP_LIFETIME: u32 = 120;
+const UDP: u8 = 17;
+
+fn nat_pmp_error(message: &str) -> QCloudError {
+    QCloudError::IpDetection(format!("NAT-PMP {message}"))
+}
+
+fn socket_error(e: std::io::Error) -> QCloudError {
+    nat_pmp_error(&format!("socket failed: {e}"))
+}
+
+/// Ask `gateway` (`ip` or `ip:port`), or the default gateway if unset, for its external
+/// address
+pub(super) async fn detect(gateway: Option<&str>) -> Result<String, QCloudError> {
+    let gateway = match gateway {
+        Some(gateway) => gateway
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                gateway
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, NAT_PMP_PORT))
+            })
+            .map_err(|_| nat_pmp_error(&format!("invalid gateway {gateway}")))?,
+        None => SocketAddr::new(
+            IpAddr::V4(default_gateway().ok_or_else(|| nat_pmp_error("no default gateway"))?),
+            NAT_PMP_PORT,
+        ),
+    };
+    let bind_addr = if gateway.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_addr).await.map_err(socket_error)?;
+    socket.connect(gateway).await.map_err(socket_error)?;
+
+    // version 0, opcode 0: external address request
+    let answer = exchange(&socket, &[0, 0]).await?;
+    if answer.len() < 4 || answer[1] != 128 {
+        return Err(nat_pmp_error("answer is not an external address response"));
+    }
+    match u16::from_be_bytes([answer[2], answer[3]]) {
+        0 if answer.len() >= 12 => {
+            Ok(Ipv4Addr::new(answer[8], answer[9], answer[10], answer[11]).to_string())
+        }
+        UNSUPPORTED_VERSION => pcp_external_address(&socket).await,
+        code => Err(nat_pmp_error(&format!(
+            "gateway answered result code {code}"
+        ))),
+    }
+}
+
+/// Send `request` until an answer arrives
+async fn exchange(socket: &UdpSocket, request: &[u8]) -> Result<Vec<u8>, QCloudError> {
+    let mut buf = [0u8; 1100];
+    for _ in 0..ATTEMPTS {
+        socket.send(request).await.map_err(socket_error)?;
+        if let Ok(len) = tokio::time::timeout(ATTEMPT_TIMEOUT, socket.recv(&mut buf)).await {
+            return Ok(buf[..len.map_err(socket_error)?].to_vec());
+        }
+    }
+    Err(nat_pmp_error("gateway didn't answer"))
+}
+
+/// PCP has no plain external address request, so a short lived mapping of the (unused) UDP
+/// port of `socket` is requested and deleted again right after
+async fn pcp_external_address(socket: &UdpSocket) -> Result<String, QCloudError> {
+    let local = socket.local_addr().map_err(socket_error)?;
+    let nonce: [u8; 12] = rand::random();
+    let answer = exchange(socket, &pcp_map_request(local, &nonce, PCP_LIFETIME)).await?;
+    if answer.len() < 60 || answer[0] != PCP_VERSION || answer[1] != PCP_RESPONSE | PCP_MAP {
+        return Err(nat_pmp_error("answer is not a PCP map response"));
+    }
+    if answer[3] != 0 {
+        return Err(nat_pmp_error(&format!(
+            "gateway answered PCP result code {}",
+            answer[3]
+        )));
+    }
+    // best effort, the mapping expires by itself anyway
+    let _ = socket.send(&pcp_map_request(local, &nonce, 0)).await;
+
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&answer[44..60]);
+    let ip = Ipv6Addr::from(octets);
+    Ok(match ip.to_ipv4_mapped() {
+        Some(ip) => ip.to_string(),
+        None => ip.to_string(),
+    })
+}
+
+/// PCP MAP request for the UDP port of `local`
+fn pcp_map_request(local: SocketAddr, nonce: &[u8; 12], lifetime: u32) -> Vec<u8> {
+    let client_ip = match local.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    let any_external = match local.ip() {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED,
+    };
+    let mut request = vec![PCP_VERSION, PCP_MAP, 0, 0];
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&client_ip.octets());
+    request.extend_from_slice(nonce);
+    request.extend_from_slice(&[UDP, 0, 0, 0]);
+    request.extend_from_slice(&local.port().to_be_bytes());
+    // no suggested external port and address
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&any_external.octets());
+    request
+}
+
+/// Gateway of the IPv4 default route, read from /proc/net/route
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        // the kernel prints the address as a number in host byte order
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_detect_reads_nat_pmp_external_address() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = socket.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (_, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let answer = [0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 7];
+            socket.send_to(&answer, peer).await.unwrap();
+        });
+        assert_eq!(detect(Some(&gateway)).await.unwrap(), "203.0.113.7");
+    }
+
+    #[tokio::test]
+    async fn test_detect_falls_back_to_pcp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = socket.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            let (_, peer) = socket.recv_from(&mut buf).await.unwrap();
+            socket
+                .send_to(&[0, 128, 0, UNSUPPORTED_VERSION as u8], peer)
+                .await
+                .unwrap();
+
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let request = buf[..len].to_vec();
+            let mut answer = vec![PCP_VERSION, PCP_RESPONSE | PCP_MAP, 0, 0];
+            answer.extend_from_slice(&request[4..8]);
+            answer.extend_from_slice(&[0u8; 16]);
+            answer.extend_from_slice(&request[24..42]);
+            answer.extend_from_slice(&[0x12, 0x34]);
+            answer.extend_from_slice(&Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped().octets());
+            socket.send_to(&answer, peer).await.unwrap();
+
+            // the mapping is deleted again
+            let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+            buf[4..8] == [0, 0, 0, 0] && len == request.len()
+        });
+        assert_eq!(detect(Some(&gateway)).await.unwrap(), "203.0.113.7");
+        assert!(handle.await.unwrap());
+    }
+}
@@ -0,0 +1,110 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use super::IpFamily;
+
+/// Range of addresses in CIDR notation, e.g. `203.0.113.0/24` or `2400:3200::/32`. A bare
+/// address is a range of that single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    /// Family of the addresses in the range
+    pub fn family(&self) -> IpFamily {
+        IpFamily::of(&self.addr)
+    }
+
+    /// Whether `ip` is in the range. Addresses of the other family never are
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(addr) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(addr) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("{s:?} is not an ip prefix"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| format!("{s:?} has an invalid prefix length"))?,
+            None => max,
+        };
+        Ok(IpPrefix { addr, len })
+    }
+}
+
+impl TryFrom<String> for IpPrefix {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<IpPrefix> for String {
+    fn from(prefix: IpPrefix) -> Self {
+        prefix.to_string()
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_contains() {
+        let prefix: IpPrefix = "203.0.113.0/24".parse().unwrap();
+        assert!(prefix.contains(&"203.0.113.7".parse().unwrap()));
+        assert!(!prefix.contains(&"203.0.114.7".parse().unwrap()));
+        assert!(!prefix.contains(&"2400:3200::1".parse().unwrap()));
+
+        let prefix: IpPrefix = "2400:3200::/32".parse().unwrap();
+        assert!(prefix.contains(&"2400:3200:1::1".parse().unwrap()));
+        assert!(!prefix.contains(&"2400:3201::1".parse().unwrap()));
+
+        let prefix: IpPrefix = "0.0.0.0/0".parse().unwrap();
+        assert!(prefix.contains(&"8.8.8.8".parse().unwrap()));
+
+        let prefix: IpPrefix = "1.2.3.4".parse().unwrap();
+        assert_eq!(prefix.to_string(), "1.2.3.4/32");
+        assert!(prefix.contains(&"1.2.3.4".parse().unwrap()));
+        assert!(!prefix.contains(&"1.2.3.5".parse().unwrap()));
+
+        assert!("1.2.3.0/33".parse::<IpPrefix>().is_err());
+        assert!("<html>".parse::<IpPrefix>().is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! RFC 5389 STUN binding requests, the server answers with the reflexive transport address
+//! it saw the request coming from
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use tokio::net::{lookup_host, UdpSocket};
+
+use super::IpFamily;
+use crate::QCloudError;
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+/// UDP is unreliable, the request is sent again after each timeout
+const ATTEMPTS: u32 = 3;
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn stun_error(message: &str) -> QCloudError {
+    QCloudError::IpDetection(format!("STUN {message}"))
+}
+
+fn socket_error(e: std::io::Error) -> QCloudError {
+    stun_error(&format!("socket failed: {e}"))
+}
+
+/// Send a binding request to `server` (`host:port`) over `family` and return the mapped
+/// address
+pub(super) async fn detect(server: &str, family: IpFamily) -> Result<String, QCloudError> {
+    let addr = lookup_host(server)
+        .await
+        .map_err(socket_error)?
+        .find(|addr| family.contains(addr))
+        .ok_or_else(|| stun_error(&format!("server {server} has no {family} address")))?;
+    let bind_addr = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_addr).await.map_err(socket_error)?;
+    socket.connect(addr).await.map_err(socket_error)?;
+
+    let transaction_id: [u8; 12] = rand::random();
+    let request = binding_request(&transaction_id);
+    let mut buf = [0u8; 1500];
+    for _ in 0..ATTEMPTS {
+        socket.send(&request).await.map_err(socket_error)?;
+        match tokio::time::timeout(ATTEMPT_TIMEOUT, socket.recv(&mut buf)).await {
+            Ok(len) => {
+                let len = len.map_err(socket_error)?;
+                return parse_binding_response(&buf[..len], &transaction_id)
+                    .map(|ip| ip.to_string());
+            }
+            Err(_) => continue,
+        }
+    }
+    Err(stun_error(&format!("server {server} didn't answer")))
+}
+
+fn binding_request(transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // no attributes
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction_id);
+    request
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Result<u16, QCloudError> {
+    packet
+        .get(pos..pos + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| stun_error("answer is truncated"))
+}
+
+/// Address of XOR-MAPPED-ADDRESS, or of MAPPED-ADDRESS sent by RFC 3489 servers
+fn parse_binding_response(packet: &[u8], transaction_id: &[u8; 12]) -> Result<IpAddr, QCloudError> {
+    if read_u16(packet, 0)? != BINDING_SUCCESS {
+        return Err(stun_error("answer is not a binding success response"));
+    }
+    if packet.get(4..8) != Some(&MAGIC_COOKIE.to_be_bytes()[..])
+        || packet.get(8..20) != Some(&transaction_id[..])
+    {
+        return Err(stun_error("answer doesn't match the request"));
+    }
+    let end = (20 + read_u16(packet, 2)? as usize).min(packet.len());
+
+    let mut mapped = None;
+    let mut pos = 20;
+    while pos + 4 <= end {
+        let attr_type = read_u16(packet, pos)?;
+        let attr_len = read_u16(packet, pos + 2)? as usize;
+        let value = packet
+            .get(pos + 4..pos + 4 + attr_len)
+            .ok_or_else(|| stun_error("answer is truncated"))?;
+        match attr_type {
+            XOR_MAPPED_ADDRESS => return address(value, Some(transaction_id)),
+            MAPPED_ADDRESS => mapped = Some(address(value, None)?),
+            _ => {}
+        }
+        // attributes are padded to a multiple of 4 bytes
+        pos += 4 + attr_len.div_ceil(4) * 4;
+    }
+    mapped.ok_or_else(|| stun_error("answer has no mapped address"))
+}
+
+/// Address of a (XOR-)MAPPED-ADDRESS value, XORed with the magic cookie and the
+/// transaction id if `transaction_id` is given
+fn address(value: &[u8], transaction_id: Option<&[u8; 12]>) -> Result<IpAddr, QCloudError> {
+    let mut key = MAGIC_COOKIE.to_be_bytes().to_vec();
+    key.extend_from_slice(transaction_id.unwrap_or(&[0; 12]));
+    let xor = |bytes: &[u8]| -> Vec<u8> {
+        match transaction_id {
+            Some(_) => bytes.iter().zip(key.iter()).map(|(b, k)| b ^ k).collect(),
+            None => bytes.to_vec(),
+        }
+    };
+    // value is: reserved, family, port, address
+    match (value.get(1), value.len()) {
+        (Some(0x01), 8) => {
+            let octets: [u8; 4] = xor(&value[4..8]).try_into().unwrap_or_default();
+            Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        (Some(0x02), 20) => {
+            let octets: [u8; 16] = xor(&value[4..20]).try_into().unwrap_or_default();
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => Err(stun_error("answer has an unknown address family")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Binding success response to `request` with `attr_type` holding `ip`
+    fn binding_response(request: &[u8], attr_type: u16, ip: IpAddr) -> Vec<u8> {
+        let (family, octets) = match ip {
+            IpAddr::V4(ip) => (0x01u8, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (0x02u8, ip.octets().to_vec()),
+        };
+        let octets: Vec<u8> = if attr_type == XOR_MAPPED_ADDRESS {
+            octets
+                .iter()
+                .zip(request[4..20].iter())
+                .map(|(b, k)| b ^ k)
+                .collect()
+        } else {
+            octets
+        };
+        // an unknown attribute with padding goes first
+        let mut attrs = vec![0x80, 0x22, 0x00, 0x03, b'a', b'b', b'c', 0x00];
+        attrs.extend_from_slice(&attr_type.to_be_bytes());
+        attrs.extend_from_slice(&(4 + octets.len() as u16).to_be_bytes());
+        attrs.extend_from_slice(&[0, family, 0x12, 0x34]);
+        attrs.extend_from_slice(&octets);
+
+        let mut response = BINDING_SUCCESS.to_be_bytes().to_vec();
+        response.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+        response.extend_from_slice(&request[4..20]);
+        response.extend_from_slice(&attrs);
+        response
+    }
+
+    #[tokio::test]
+    async fn test_detect_reads_xor_mapped_address_from_stand_in_server() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let ip = "203.0.113.7".parse().unwrap();
+            let response = binding_response(&buf[..len], XOR_MAPPED_ADDRESS, ip);
+            socket.send_to(&response, peer).await.unwrap();
+        });
+        assert_eq!(
+            detect(&server, IpFamily::Ipv4).await.unwrap(),
+            "203.0.113.7"
+        );
+        assert!(detect(&server, IpFamily::Ipv6).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_ipv6_and_legacy_mapped_address() {
+        let transaction_id = [7u8; 12];
+        let request = binding_request(&transaction_id);
+        let ip: IpAddr = "2001:db8::7".parse().unwrap();
+        let response = binding_response(&request, XOR_MAPPED_ADDRESS, ip);
+        assert_eq!(
+            parse_binding_response(&response, &transaction_id).unwrap(),
+            ip
+        );
+
+        let ip: IpAddr = "198.51.100.7".parse().unwrap();
+        let response = binding_response(&request, MAPPED_ADDRESS, ip);
+        assert_eq!(
+            parse_binding_response(&response, &transaction_id).unwrap(),
+            ip
+        );
+        assert!(parse_binding_response(&response, &[8u8; 12]).is_err());
+    }
+}
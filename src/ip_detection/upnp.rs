@@ -0,0 +1,171 @@
+//! External address of the UPnP Internet Gateway Device, found by SSDP and asked by SOAP
+
+use std::time::Duration;
+
+use reqwest::Url;
+use tokio::net::UdpSocket;
+
+use crate::QCloudError;
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const SSDP_TIMEOUT: Duration = Duration::from_secs(3);
+const IGD_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Services offering GetExternalIPAddress, for DHCP/static and PPPoE WAN links
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+fn upnp_error(message: &str) -> QCloudError {
+    QCloudError::IpDetection(format!("UPnP {message}"))
+}
+
+fn transport_error(e: impl std::fmt::Display) -> QCloudError {
+    upnp_error(&format!("request failed: {e}"))
+}
+
+/// Ask the gateway whose device description is at `location`, or the first one answering
+/// SSDP discovery if unset, for its external address
+pub(super) async fn detect(
+    client: &reqwest::Client,
+    location: Option<&str>,
+) -> Result<String, QCloudError> {
+    let location = match location {
+        Some(location) => location.to_string(),
+        None => discover().await?,
+    };
+    let description = client
+        .get(&location)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(transport_error)?
+        .text()
+        .await
+        .map_err(transport_error)?;
+    let (service, control_url) = wan_service(&description, &location)?;
+
+    let body = format!(
+        r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:GetExternalIPAddress xmlns:u="{service}"/></s:Body></s:Envelope>"#
+    );
+    let answer = client
+        .post(control_url)
+        .header("Content-Type", r#"text/xml; charset="utf-8""#)
+        .header("SOAPAction", format!(r#""{service}#GetExternalIPAddress""#))
+        .body(body)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(transport_error)?
+        .text()
+        .await
+        .map_err(transport_error)?;
+    element(&answer, "NewExternalIPAddress")
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+        .ok_or_else(|| upnp_error("gateway has no external address"))
+}
+
+/// Location of the device description of the first gateway answering M-SEARCH
+async fn discover() -> Result<String, QCloudError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(transport_error)?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {IGD_DEVICE}\r\n\r\n"
+    );
+    socket
+        .send_to(search.as_bytes(), SSDP_ADDR)
+        .await
+        .map_err(transport_error)?;
+
+    let mut buf = [0u8; 2048];
+    let deadline = tokio::time::Instant::now() + SSDP_TIMEOUT;
+    loop {
+        let len = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(len) => len.map_err(transport_error)?,
+            Err(_) => return Err(upnp_error("no gateway answered discovery")),
+        };
+        let answer = String::from_utf8_lossy(&buf[..len]);
+        let location = answer.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        });
+        if let Some(location) = location {
+            return Ok(location);
+        }
+    }
+}
+
+/// Text of the first `<name>` element of `xml`, namespace prefixes aside
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("{name}>")).map(|i| i + name.len() + 1)?;
+    let end = start + xml[start..].find("</")?;
+    Some(&xml[start..end])
+}
+
+/// Service type and absolute control URL of the WAN connection service of the device
+/// `description` downloaded from `location`
+fn wan_service(description: &str, location: &str) -> Result<(String, Url), QCloudError> {
+    let base = element(description, "URLBase").unwrap_or(location).trim();
+    let base = Url::parse(base).map_err(transport_error)?;
+    for service in description.split("<service>").skip(1) {
+        let service_type = element(service, "serviceType").map(str::trim);
+        let control_url = element(service, "controlURL").map(str::trim);
+        if let (Some(service_type), Some(control_url)) = (service_type, control_url) {
+            if WAN_SERVICES.contains(&service_type) {
+                let control_url = base.join(control_url).map_err(transport_error)?;
+                return Ok((service_type.to_string(), control_url));
+            }
+        }
+    }
+    Err(upnp_error("gateway has no WAN connection service"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::stand_in_server;
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+        <controlURL>/ctl/L3F</controlURL>
+      </service>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+        <controlURL>/ctl/IPConn</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>"#;
+
+    const SOAP_ANSWER: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body>
+<u:GetExternalIPAddressResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>
+</u:GetExternalIPAddressResponse></s:Body></s:Envelope>"#;
+
+    #[tokio::test]
+    async fn test_detect_asks_wan_connection_service() {
+        let (base_url, handle) =
+            stand_in_server(vec![DESCRIPTION.to_string(), SOAP_ANSWER.to_string()]).await;
+        let location = format!("{base_url}/rootDesc.xml");
+        let ip = detect(&reqwest::Client::new(), Some(&location))
+            .await
+            .unwrap();
+        assert_eq!(ip, "203.0.113.7");
+
+        let requests = handle.await.unwrap();
+        assert!(requests[1].starts_with("POST /ctl/IPConn "));
+        assert!(requests[1]
+            .contains("urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress"));
+    }
+}
@@ -3,6 +3,18 @@ mod web_client_native;
 mod qcloud_sign;
 mod qcloud_web_client;
 mod dto;
+mod config;
+mod error;
+mod firewall_filter;
+mod firewall_plan;
+mod ip_detection;
+mod state;
+mod drift;
+mod audit;
+mod retry;
+mod rate_limit;
+#[cfg(test)]
+mod test_utils;
 // mod firewall_payload_tpl;
 
 #[macro_use]
@@ -13,5 +25,15 @@ pub use web_client_native::*;
 pub use web_client::*;
 pub use qcloud_sign::*;
 pub use qcloud_web_client::*;
+pub use error::*;
+pub use config::*;
+pub use firewall_filter::*;
+pub use firewall_plan::*;
+pub use ip_detection::*;
+pub use state::*;
+pub use drift::*;
+pub use audit::*;
+pub use retry::*;
+pub use rate_limit::*;
 // pub use firewall_payload_tpl::*;
 pub use dto::{response, request, rust_struct, c_struct};
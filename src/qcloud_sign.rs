@@ -28,6 +28,7 @@ fn hmac_256(key: &[u8], input: &str) -> (Vec<u8>, String) {
     (bits.to_vec(), hex)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn make_auth_string_all_in_one(
     host: &str,
     content_type: &str,
@@ -1,69 +1,124 @@
-use std::{error, fmt};
+use std::future::Future;
 
 use crate::{
-    make_auth_string_all_in_one, request::CreateDeleteFirewallRulesRequest,
-    response::FirewallRuleSet,
+    firewall_plan::rule_matches,
+    make_auth_string_all_in_one, QCloudError, RateLimiter, RetryPolicy,
+    request::{CreateDeleteFirewallRulesRequest, DescribeFirewallRulesRequest, FirewallRule},
+    response::{
+        CommonResponse, CreateDeleteFirewallRulesResponse, DescribeFirewallRulesResponse,
+        FirewallRuleSet, ResponseRoot,
+    },
 };
 use chrono::Utc;
+use futures::{stream, Stream, TryStreamExt};
 use reqwest::header::{CONTENT_TYPE, HOST};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Region used when none is configured.
+pub const DEFAULT_REGION: &str = "ap-shanghai";
+/// Global Lighthouse endpoint, which routes by the `X-TC-Region` header.
+pub const DEFAULT_ENDPOINT: &str = "lighthouse.tencentcloudapi.com";
+
+/// API version of Lighthouse actions
+pub const LIGHTHOUSE_API_VERSION: &str = "2020-03-24";
+
+/// Maximum `Limit` accepted by DescribeFirewallRules
+pub const FIREWALL_RULES_PAGE_SIZE: i64 = 100;
+
+lazy_static! {
+    // shared by all clients, so connections are kept alive between calls
+    static ref API_CLIENT: reqwest::Client = reqwest::Client::new();
+}
+
+/// Build the regional endpoint of a service,
+/// e.g. `lighthouse.ap-guangzhou.tencentcloudapi.com`.
+pub fn regional_endpoint(service: &str, region: &str) -> String {
+    format!("{service}.{region}.tencentcloudapi.com")
+}
 
 pub struct QCloudWebClient {
+    scheme: String,
     host: String,
+    region: String,
     content_type: String,
     instance_id: String,
     secret_id: String,
     secret_key: String,
     service: String,
-}
-
-#[derive(Debug, Clone)]
-pub struct QCloudError(pub String);
-
-impl error::Error for QCloudError {}
-
-impl fmt::Display for QCloudError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
+    retry: RetryPolicy,
 }
 
 impl QCloudWebClient {
+    /// Create a client.
+    ///
+    /// `endpoint` is either a bare host such as `lighthouse.ap-guangzhou.tencentcloudapi.com`
+    /// (https is assumed) or a base URL such as `http://127.0.0.1:8080` pointing to a local
+    /// stand-in server.
     pub fn new(
-        host: String,
+        endpoint: String,
+        region: String,
         content_type: String,
         instance_id: String,
         secret_id: String,
         secret_key: String,
         service: String,
     ) -> Self {
+        let (scheme, host) = match endpoint.split_once("://") {
+            Some((scheme, host)) => (scheme.to_string(), host.trim_end_matches('/').to_string()),
+            None => ("https".to_string(), endpoint.trim_end_matches('/').to_string()),
+        };
         Self {
+            scheme,
             host,
+            region,
             content_type,
             instance_id,
             secret_id,
             secret_key,
             service,
+            retry: RetryPolicy::default(),
         }
     }
 
-    /// Query all firewall rules
+    /// Retry failed calls according to `retry` instead of the default policy
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Host the requests are signed for and sent to
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Region sent in `X-TC-Region`
+    pub fn region(&self) -> &str {
+        &self.region
+    }
+
+    /// Base URL the requests are posted to, e.g. `https://lighthouse.tencentcloudapi.com`
+    pub fn base_url(&self) -> String {
+        format!("{}://{}", self.scheme, self.host)
+    }
+
+    /// Send a signed TC3-HMAC-SHA256 request of `action` and return the raw response body.
+    /// The request waits for the process-wide rate limit of the action, see [`RateLimits`].
     ///
     /// Set VERBOSE=1 to print raw response body
     ///
-    /// # Panics
-    ///
-    /// Panics if .
-    ///
     /// # Errors
     ///
-    /// This function will return an error if .
-    pub async fn query_all_firewall_rules(
+    /// This function will return an error if the payload can't be serialized or the request
+    /// fails on transport level.
+    async fn send<Req: Serialize>(
         &self,
-    ) -> Result<Vec<FirewallRuleSet>, Box<dyn std::error::Error>> {
-        let payload = format!(
-            "{{\"InstanceId\":\"{}\",\"Offset\":0,\"Limit\":100}}",
-            self.instance_id
-        );
+        action: &str,
+        version: &str,
+        req: &Req,
+    ) -> Result<String, QCloudError> {
+        // wait before signing, the signature carries the time it's made at
+        RateLimiter::global().acquire(action).await;
+        let payload = serde_json::to_string(req)?;
         let timestamp = Utc::now().timestamp();
         let date = Utc::now().format("%Y-%m-%d").to_string();
         let auth_string = make_auth_string_all_in_one(
@@ -77,22 +132,18 @@ impl QCloudWebClient {
             &self.service,
         );
 
-        let host = "lighthouse.tencentcloudapi.com";
-        let client = reqwest::Client::new();
-        // let res: crate::response::DescribeFirewallRulesResponseRoot = client
-        let res = client
-            .post(format!("https://{host}"))
+        let res = API_CLIENT
+            .post(self.base_url())
             .header("Authorization", auth_string)
             .header(CONTENT_TYPE, &self.content_type)
-            .header(HOST, host)
-            .header("X-TC-Action", "DescribeFirewallRules")
+            .header(HOST, &self.host)
+            .header("X-TC-Action", action)
             .header("X-TC-Timestamp", format!("{timestamp}"))
-            .header("X-TC-Version", "2020-03-24")
-            .header("X-TC-Region", "ap-shanghai")
+            .header("X-TC-Version", version)
+            .header("X-TC-Region", &self.region)
             .body(payload)
             .send()
             .await?
-            // .json()
             .text()
             .await?;
 
@@ -100,19 +151,146 @@ impl QCloudWebClient {
             println!("{res}");
         }
 
-        let response: crate::response::DescribeFirewallRulesResponseRoot =
-            serde_json::from_str(&res).unwrap();
+        Ok(res)
+    }
+
+    /// Invoke any Tencent Cloud API `action` of the service this client is created for.
+    ///
+    /// The request is signed, the `{"Response": {...}}` envelope is unwrapped and an `Error`
+    /// object in the response is turned into an error. To call another service (e.g. VPC or
+    /// DNSPod), create the client with that service name and its endpoint.
+    ///
+    /// Read-only `Describe*` actions are retried according to the retry policy, any other
+    /// action is sent once as it may not be safe to repeat.
+    ///
+    /// Set VERBOSE=1 to print raw response body
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails, the response can't be decoded
+    /// into `Resp` or Tencent Cloud returns an error.
+    pub async fn call<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        action: &str,
+        version: &str,
+        req: &Req,
+    ) -> Result<Resp, QCloudError> {
+        if action.starts_with("Describe") {
+            self.with_retry(action, |_| self.call_once(action, version, req))
+                .await
+        } else {
+            self.call_once(action, version, req).await
+        }
+    }
 
-        let mut result = Vec::new();
+    async fn call_once<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        action: &str,
+        version: &str,
+        req: &Req,
+    ) -> Result<Resp, QCloudError> {
+        let res = self.send(action, version, req).await?;
+        parse_response(&res)
+    }
 
-        if let Some(resp) = response.response {
-            if let Some(e) = resp.error {
-                return Err(Box::new(QCloudError(e.message)));
+    /// Run `attempt` until it succeeds, fails with an error the retry policy doesn't retry or
+    /// runs out of attempts, waiting with exponential backoff in between. `attempt` gets the
+    /// number of attempts made before.
+    async fn with_retry<T, F, Fut>(&self, action: &str, mut attempt: F) -> Result<T, QCloudError>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T, QCloudError>>,
+    {
+        let mut attempts = 0;
+        loop {
+            match attempt(attempts).await {
+                Err(err)
+                    if attempts + 1 < self.retry.max_attempts && self.retry.is_retryable(&err) =>
+                {
+                    let delay = self.retry.delay(attempts);
+                    eprintln!("{action} failed, retrying in {}ms: {err}", delay.as_millis());
+                    tokio::time::sleep(delay).await;
+                    attempts += 1;
+                }
+                result => return result,
             }
-            result = resp.firewall_rule_set.unwrap();
         }
+    }
 
-        Ok(result)
+    /// Rules of `rules` found in the live firewall, and those not found
+    async fn partition_live(
+        &self,
+        rules: &[FirewallRule],
+    ) -> Result<(Vec<FirewallRule>, Vec<FirewallRule>), QCloudError> {
+        let live = self.query_all_firewall_rules().await?;
+        Ok(rules
+            .iter()
+            .cloned()
+            .partition(|rule| live.iter().any(|item| rule_matches(rule, item))))
+    }
+
+    /// Stream all firewall rules, fetching pages of `FIREWALL_RULES_PAGE_SIZE` rules until
+    /// `TotalCount` rules are received.
+    ///
+    /// Set VERBOSE=1 to print raw response body
+    pub fn firewall_rules_stream(
+        &self,
+    ) -> impl Stream<Item = Result<FirewallRuleSet, QCloudError>> + '_ {
+        stream::try_unfold(Some(0), move |offset| async move {
+            let Some(offset) = offset else {
+                return Ok::<_, QCloudError>(None);
+            };
+            let payload = DescribeFirewallRulesRequest {
+                instance_id: self.instance_id.clone(),
+                offset,
+                limit: FIREWALL_RULES_PAGE_SIZE,
+            };
+            let resp: DescribeFirewallRulesResponse = self
+                .call("DescribeFirewallRules", LIGHTHOUSE_API_VERSION, &payload)
+                .await?;
+
+            let page = resp.firewall_rule_set.unwrap_or_default();
+            let next_offset = offset + page.len() as i64;
+            // an empty page stops paging even if TotalCount claims more rules
+            let next = if page.is_empty() || next_offset >= resp.total_count.unwrap_or(0) {
+                None
+            } else {
+                Some(next_offset)
+            };
+            Ok(Some((stream::iter(page.into_iter().map(Ok)), next)))
+        })
+        .try_flatten()
+    }
+
+    /// Query all firewall rules
+    ///
+    /// Set VERBOSE=1 to print raw response body
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if any page fails to be queried.
+    pub async fn query_all_firewall_rules(
+        &self,
+    ) -> Result<Vec<FirewallRuleSet>, QCloudError> {
+        self.firewall_rules_stream().try_collect().await
+    }
+
+    /// Current `FirewallVersion` of the instance, which changes with every modification of its
+    /// firewall
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails.
+    pub async fn query_firewall_version(&self) -> Result<Option<i64>, QCloudError> {
+        let payload = DescribeFirewallRulesRequest {
+            instance_id: self.instance_id.clone(),
+            offset: 0,
+            limit: 1,
+        };
+        let resp: DescribeFirewallRulesResponse = self
+            .call("DescribeFirewallRules", LIGHTHOUSE_API_VERSION, &payload)
+            .await?;
+        Ok(resp.firewall_version)
     }
 
     /// Query firewall rules by given description
@@ -125,7 +303,7 @@ impl QCloudWebClient {
     pub async fn query_firewall_rules_by_description(
         &self,
         desc_list: &[String],
-    ) -> Result<Vec<FirewallRuleSet>, Box<dyn std::error::Error>> {
+    ) -> Result<Vec<FirewallRuleSet>, QCloudError> {
         let res = self.query_all_firewall_rules().await?;
         // let mut result = Vec::new();
         let result = res
@@ -168,59 +346,45 @@ impl QCloudWebClient {
     pub async fn qcloud_delete_firewall_rules(
         &self,
         payload: &CreateDeleteFirewallRulesRequest,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        let payload_new = payload.clone();
-        // for rule in payload_new.firewall_rules.iter_mut() {
-        //     // rule.cidr_block = None;
-        // }
-        let payload_str = serde_json::to_string(&payload_new).unwrap();
-        let timestamp = Utc::now().timestamp();
-        let date = Utc::now().format("%Y-%m-%d").to_string();
-        let auth_string = make_auth_string_all_in_one(
-            &self.host,
-            &self.content_type,
-            &payload_str,
-            timestamp,
-            &date,
-            &self.secret_id,
-            &self.secret_key,
-            &self.service,
-        );
-
-        let host = "lighthouse.tencentcloudapi.com";
-        let client = reqwest::Client::new();
-        // let res: crate::response::CreateDeleteFirewallRulesResponseRoot = client
-        let res = client
-            .post(format!("https://{host}"))
-            .header("Authorization", auth_string)
-            .header(CONTENT_TYPE, &self.content_type)
-            .header(HOST, host)
-            .header("X-TC-Action", "DeleteFirewallRules")
-            .header("X-TC-Timestamp", format!("{timestamp}"))
-            .header("X-TC-Version", "2020-03-24")
-            .header("X-TC-Region", "ap-shanghai")
-            .body(payload_str)
-            .send()
-            .await?
-            // .json()
-            .text()
-            .await?;
-
-        if dotenv::var("VERBOSE").unwrap_or_default() == "1" {
-            println!("{res}");
-        }
+    ) -> Result<bool, QCloudError> {
+        self.delete_firewall_rules(payload).await.map(|_| true)
+    }
 
-        let response: crate::response::CreateDeleteFirewallRulesResponseRoot =
-            serde_json::from_str(&res).unwrap();
+    /// Delete firewall rules, returning the `RequestId` of the request. Rules already gone are
+    /// not an error. A retry only deletes the rules still in the firewall, None is returned if
+    /// there are none left
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails.
+    pub async fn delete_firewall_rules(
+        &self,
+        payload: &CreateDeleteFirewallRulesRequest,
+    ) -> Result<Option<String>, QCloudError> {
+        let res: Result<CreateDeleteFirewallRulesResponse, QCloudError> = self
+            .with_retry("DeleteFirewallRules", |attempts| async move {
+                let mut payload = payload.clone();
+                if attempts > 0 {
+                    // the failed attempt may have deleted the rules before the connection
+                    // dropped, only what is left gets deleted
+                    payload.firewall_rules = self.partition_live(&payload.firewall_rules).await?.0;
+                    if payload.firewall_rules.is_empty() {
+                        return Ok(CreateDeleteFirewallRulesResponse::default());
+                    }
+                }
+                self.call("DeleteFirewallRules", LIGHTHOUSE_API_VERSION, &payload)
+                    .await
+            })
+            .await;
 
-        if let Some(e) = response.response.error {
+        match res {
+            Ok(response) => Ok(response.request_id),
             // if error is caused FirewallRulesNotFound, we don't need to care about
-            if e.code != "ResourceNotFound.FirewallRulesNotFound" {
-                return Err(Box::new(QCloudError(e.message)));
+            Err(e) if e.code() == Some("ResourceNotFound.FirewallRulesNotFound") => {
+                Ok(e.request_id().map(str::to_string))
             }
+            Err(e) => Err(e),
         }
-
-        Ok(true)
     }
 
     /// Create firewall rules.
@@ -237,54 +401,53 @@ impl QCloudWebClient {
     pub async fn qcloud_create_firewall_rules(
         &self,
         payload: &CreateDeleteFirewallRulesRequest,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        let payload_new = payload.clone();
-        let payload_str = serde_json::to_string(&payload_new).unwrap();
-        let timestamp = Utc::now().timestamp();
-        let date = Utc::now().format("%Y-%m-%d").to_string();
-        let auth_string = make_auth_string_all_in_one(
-            &self.host,
-            &self.content_type,
-            &payload_str,
-            timestamp,
-            &date,
-            &self.secret_id,
-            &self.secret_key,
-            &self.service,
-        );
+    ) -> Result<bool, QCloudError> {
+        self.create_firewall_rules(payload).await.map(|_| true)
+    }
 
-        let host = "lighthouse.tencentcloudapi.com";
-        let client = reqwest::Client::new();
-        // let res: crate::response::CreateDeleteFirewallRulesResponseRoot = client
-        let res = client
-            .post(format!("https://{host}"))
-            .header("Authorization", auth_string)
-            .header(CONTENT_TYPE, &self.content_type)
-            .header(HOST, host)
-            .header("X-TC-Action", "CreateFirewallRules")
-            .header("X-TC-Timestamp", format!("{timestamp}"))
-            .header("X-TC-Version", "2020-03-24")
-            .header("X-TC-Region", "ap-shanghai")
-            .body(payload_str)
-            .send()
-            .await?
-            // .json()
-            .text()
+    /// Create firewall rules, returning the `RequestId` of the request. A retry only creates the
+    /// rules not in the firewall yet, None is returned if the failed attempt created them all
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails.
+    pub async fn create_firewall_rules(
+        &self,
+        payload: &CreateDeleteFirewallRulesRequest,
+    ) -> Result<Option<String>, QCloudError> {
+        let response: CreateDeleteFirewallRulesResponse = self
+            .with_retry("CreateFirewallRules", |attempts| async move {
+                let mut payload = payload.clone();
+                if attempts > 0 {
+                    // the failed attempt may have created the rules before the connection
+                    // dropped, creating them again would duplicate them
+                    payload.firewall_rules = self.partition_live(&payload.firewall_rules).await?.1;
+                    if payload.firewall_rules.is_empty() {
+                        return Ok(CreateDeleteFirewallRulesResponse::default());
+                    }
+                }
+                self.call("CreateFirewallRules", LIGHTHOUSE_API_VERSION, &payload)
+                    .await
+            })
             .await?;
 
-        if dotenv::var("VERBOSE").unwrap_or_default() == "1" {
-            println!("{res}");
-        }
-
-        let response: crate::response::CreateDeleteFirewallRulesResponseRoot =
-            serde_json::from_str(&res).unwrap();
-
-        if let Some(e) = response.response.error {
-            return Err(Box::new(QCloudError(e.message)));
-        }
+        Ok(response.request_id)
+    }
+}
 
-        Ok(true)
+/// Unwrap the `{"Response": {...}}` envelope of `body` into `Resp`, turning an `Error` object
+/// into an error.
+fn parse_response<Resp: DeserializeOwned>(body: &str) -> Result<Resp, QCloudError> {
+    let common: ResponseRoot<CommonResponse> = serde_json::from_str(body)?;
+    if let Some(e) = common.response.error {
+        return Err(QCloudError::Api {
+            code: e.code,
+            message: e.message,
+            request_id: common.response.request_id,
+        });
     }
+    let response: ResponseRoot<Resp> = serde_json::from_str(body)?;
+    Ok(response.response)
 }
 
 #[cfg(test)]
@@ -295,6 +458,7 @@ mod tests {
 
     #[allow(unused_imports)]
     use super::*;
+    use crate::test_utils::{describe_page, request_action, rule, stand_in_server};
 
     #[tokio::test]
     async fn test_qcloud_query_firewall_rules_by_description_not_exists() {
@@ -303,7 +467,8 @@ mod tests {
         // let secret_key = "123";
 
         let qcloud_webclient = QCloudWebClient::new(
-            "lighthouse.tencentcloudapi.com".to_string(),
+            DEFAULT_ENDPOINT.to_string(),
+            DEFAULT_REGION.to_string(),
             "application/json".to_string(),
             "lhins-3jq1gki4".to_string(),
             secret_id.to_string(),
@@ -324,7 +489,8 @@ mod tests {
         let secret_key = dotenv::var("SECRETKEY").expect("Please specific SECRETKEY variable in .env file. You can get secret_key from qcloud web portal.");
 
         let qcloud_webclient = QCloudWebClient::new(
-            "lighthouse.tencentcloudapi.com".to_string(),
+            DEFAULT_ENDPOINT.to_string(),
+            DEFAULT_REGION.to_string(),
             "application/json".to_string(),
             "lhins-3jq1gki4".to_string(),
             secret_id.to_string(),
@@ -353,7 +519,8 @@ mod tests {
         let secret_key = dotenv::var("SECRETKEY").expect("Please specific SECRETKEY variable in .env file. You can get secret_key from qcloud web portal.");
 
         let qcloud_webclient = QCloudWebClient::new(
-            "lighthouse.tencentcloudapi.com".to_string(),
+            DEFAULT_ENDPOINT.to_string(),
+            DEFAULT_REGION.to_string(),
             "application/json".to_string(),
             "lhins-3jq1gki4".to_string(),
             secret_id.to_string(),
@@ -382,7 +549,8 @@ mod tests {
         let secret_id = "123";
 
         let qcloud_webclient = QCloudWebClient::new(
-            "lighthouse.tencentcloudapi.com".to_string(),
+            DEFAULT_ENDPOINT.to_string(),
+            DEFAULT_REGION.to_string(),
             "application/json".to_string(),
             "lhins-3jq1gki4".to_string(),
             secret_id.to_string(),
@@ -402,4 +570,160 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_endpoint_defaults_to_https() {
+        let qcloud_webclient = QCloudWebClient::new(
+            regional_endpoint("lighthouse", "ap-guangzhou"),
+            "ap-guangzhou".to_string(),
+            "application/json".to_string(),
+            "lhins-3jq1gki4".to_string(),
+            "id".to_string(),
+            "key".to_string(),
+            "lighthouse".to_string(),
+        );
+        assert_eq!(
+            qcloud_webclient.base_url(),
+            "https://lighthouse.ap-guangzhou.tencentcloudapi.com"
+        );
+        assert_eq!(qcloud_webclient.region(), "ap-guangzhou");
+    }
+
+    #[test]
+    fn test_endpoint_with_custom_base_url() {
+        let qcloud_webclient = QCloudWebClient::new(
+            "http://127.0.0.1:8080/".to_string(),
+            DEFAULT_REGION.to_string(),
+            "application/json".to_string(),
+            "lhins-3jq1gki4".to_string(),
+            "id".to_string(),
+            "key".to_string(),
+            "lighthouse".to_string(),
+        );
+        assert_eq!(qcloud_webclient.base_url(), "http://127.0.0.1:8080");
+        assert_eq!(qcloud_webclient.host(), "127.0.0.1:8080");
+    }
+
+    fn stand_in_client(base_url: String) -> QCloudWebClient {
+        QCloudWebClient::new(
+            base_url,
+            "ap-guangzhou".to_string(),
+            "application/json".to_string(),
+            "lhins-3jq1gki4".to_string(),
+            "id".to_string(),
+            "key".to_string(),
+            "lighthouse".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_call_unwraps_response_envelope() {
+        let (base_url, handle) = stand_in_server(vec![r#"{"Response":{"TotalCount":1,"FirewallRuleSet":[{"Protocol":"TCP","Port":"443","CidrBlock":"1.2.3.4","Action":"ACCEPT","FirewallRuleDescription":"https"}],"RequestId":"req-1"}}"#.to_string()]).await;
+        let qcloud_webclient = stand_in_client(base_url);
+        let request = DescribeFirewallRulesRequest {
+            instance_id: "lhins-3jq1gki4".to_string(),
+            offset: 0,
+            limit: 100,
+        };
+        let response: DescribeFirewallRulesResponse = qcloud_webclient
+            .call("DescribeFirewallRules", LIGHTHOUSE_API_VERSION, &request)
+            .await
+            .unwrap();
+        assert_eq!(response.request_id, "req-1");
+        assert_eq!(response.firewall_rule_set.unwrap()[0].cidr_block, "1.2.3.4");
+
+        let requests = handle.await.unwrap();
+        let raw = requests[0].to_lowercase();
+        assert!(raw.contains("x-tc-action: describefirewallrules"));
+        assert!(raw.contains("x-tc-region: ap-guangzhou"));
+        assert!(raw.contains("authorization: tc3-hmac-sha256 credential=id/"));
+    }
+
+    #[tokio::test]
+    async fn test_call_returns_error_object() {
+        let (base_url, _handle) = stand_in_server(vec![r#"{"Response":{"Error":{"Code":"AuthFailure.SecretIdNotFound","Message":"The SecretId is not found"},"RequestId":"req-2"}}"#.to_string()]).await;
+        let qcloud_webclient = stand_in_client(base_url);
+        let result: Result<DescribeFirewallRulesResponse, _> = qcloud_webclient
+            .call("DescribeFirewallRules", LIGHTHOUSE_API_VERSION, &serde_json::json!({}))
+            .await;
+        let err = result.unwrap_err();
+        assert_eq!(err.code(), Some("AuthFailure.SecretIdNotFound"));
+        assert_eq!(err.request_id(), Some("req-2"));
+        assert_eq!(format!("{}", err), "The SecretId is not found");
+    }
+
+    #[tokio::test]
+    async fn test_query_all_firewall_rules_pages_through_total_count() {
+        let (base_url, handle) =
+            stand_in_server(vec![describe_page(0, 100, 150), describe_page(100, 50, 150)]).await;
+        let qcloud_webclient = stand_in_client(base_url);
+        let rules = qcloud_webclient.query_all_firewall_rules().await.unwrap();
+        assert_eq!(rules.len(), 150);
+        assert_eq!(rules[149].firewall_rule_description.as_deref(), Some("rule 149"));
+
+        let requests = handle.await.unwrap();
+        assert!(requests[0].contains(r#""Offset":0,"Limit":100"#));
+        assert!(requests[1].contains(r#""Offset":100,"Limit":100"#));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_gateway_page_is_decode_error() {
+        let (base_url, _handle) =
+            stand_in_server(vec!["<html>502 Bad Gateway</html>".to_string()]).await;
+        let qcloud_webclient = stand_in_client(base_url);
+        let result = qcloud_webclient.query_all_firewall_rules().await;
+        assert!(matches!(result, Err(QCloudError::Decode(_))));
+    }
+
+    fn fast_retry() -> RetryPolicy {
+        RetryPolicy {
+            base_delay_ms: 1,
+            ..Default::default()
+        }
+    }
+
+    const LIMIT_EXCEEDED: &str = r#"{"Response":{"Error":{"Code":"RequestLimitExceeded","Message":"Too many requests"},"RequestId":"req-x"}}"#;
+
+    #[tokio::test]
+    async fn test_describe_is_retried_after_request_limit_exceeded() {
+        let (base_url, handle) =
+            stand_in_server(vec![LIMIT_EXCEEDED.to_string(), describe_page(0, 2, 2)]).await;
+        let qcloud_webclient = stand_in_client(base_url).with_retry_policy(fast_retry());
+        let rules = qcloud_webclient.query_all_firewall_rules().await.unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(handle.await.unwrap().len(), 2);
+
+        let (base_url, _handle) = stand_in_server(vec![LIMIT_EXCEEDED.to_string()]).await;
+        let qcloud_webclient = stand_in_client(base_url).with_retry_policy(RetryPolicy::never());
+        let err = qcloud_webclient.query_all_firewall_rules().await.unwrap_err();
+        assert_eq!(err.code(), Some("RequestLimitExceeded"));
+    }
+
+    #[tokio::test]
+    async fn test_create_is_not_repeated_when_failed_attempt_took_effect() {
+        let (base_url, handle) = stand_in_server(vec![
+            r#"{"Response":{"Error":{"Code":"InternalError","Message":"Timeout"},"RequestId":"req-x"}}"#
+                .to_string(),
+            describe_page(0, 1, 1),
+        ])
+        .await;
+        let qcloud_webclient = stand_in_client(base_url).with_retry_policy(fast_retry());
+        let payload = CreateDeleteFirewallRulesRequest {
+            instance_id: "lhins-3jq1gki4".to_string(),
+            firewall_rules: vec![FirewallRule {
+                port: Some("0".to_string()),
+                ..rule("rule 0", "1.2.3.4")
+            }],
+        };
+        let request_id = qcloud_webclient.create_firewall_rules(&payload).await.unwrap();
+        assert_eq!(request_id, None);
+
+        let actions: Vec<String> = handle
+            .await
+            .unwrap()
+            .iter()
+            .map(|raw| request_action(raw))
+            .collect();
+        assert_eq!(actions, ["CreateFirewallRules", "DescribeFirewallRules"]);
+    }
 }
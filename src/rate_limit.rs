@@ -0,0 +1,163 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    // shared by all clients, as Tencent Cloud counts the calls of the whole account
+    static ref RATE_LIMITER: RateLimiter = RateLimiter::new(RateLimits::default());
+}
+
+/// Requests per second sent for each API action, e.g. in the configuration file
+///
+/// ```toml
+/// [rate_limits]
+/// per_second = 10
+///
+/// [rate_limits.actions]
+/// DescribeFirewallRules = 20
+/// CreateFirewallRules = 5
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimits {
+    /// Limit of actions not listed in `actions`
+    #[serde(default = "default_per_second")]
+    pub per_second: u32,
+    /// Limit by action name, 0 sends the action without limit
+    #[serde(default)]
+    pub actions: BTreeMap<String, u32>,
+}
+
+fn default_per_second() -> u32 {
+    10
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            per_second: default_per_second(),
+            actions: BTreeMap::new(),
+        }
+    }
+}
+
+impl RateLimits {
+    /// Requests per second allowed for `action`
+    pub fn per_second(&self, action: &str) -> u32 {
+        self.actions.get(action).copied().unwrap_or(self.per_second)
+    }
+
+    /// Use these limits for all clients of the process from now on
+    pub fn apply_globally(self) {
+        RATE_LIMITER.configure(self);
+    }
+}
+
+/// Token bucket of one action, holding up to a second worth of requests. Tokens go negative
+/// for requests queued behind the ones already waiting
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token buckets by action, delaying requests over the limit instead of failing them
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    inner: Mutex<(RateLimits, HashMap<String, Bucket>)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: RateLimits) -> Self {
+        Self {
+            inner: Mutex::new((limits, HashMap::new())),
+        }
+    }
+
+    /// Limiter shared by all clients of the process
+    pub(crate) fn global() -> &'static Self {
+        &RATE_LIMITER
+    }
+
+    /// Replace the limits, starting every action with a full bucket
+    fn configure(&self, limits: RateLimits) {
+        *self.inner.lock().unwrap() = (limits, HashMap::new());
+    }
+
+    /// Take a token of `action` at `now`, returning how long the request has to wait for it
+    fn reserve(&self, action: &str, now: Instant) -> Duration {
+        let mut inner = self.inner.lock().unwrap();
+        let (limits, buckets) = &mut *inner;
+        let rate = f64::from(limits.per_second(action));
+        if rate == 0.0 {
+            return Duration::ZERO;
+        }
+        let bucket = buckets.entry(action.to_string()).or_insert(Bucket {
+            tokens: rate,
+            updated_at: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        bucket.updated_at = bucket.updated_at.max(now);
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    /// Wait until a request of `action` may be sent
+    pub(crate) async fn acquire(&self, action: &str) {
+        let wait = self.reserve(action, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bursts_are_delayed_at_the_rate_of_the_action() {
+        let limiter = RateLimiter::new(RateLimits {
+            per_second: 10,
+            actions: BTreeMap::from([
+                ("CreateFirewallRules".to_string(), 2),
+                ("DescribeInstances".to_string(), 0),
+            ]),
+        });
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(
+                limiter.reserve("DescribeFirewallRules", now),
+                Duration::ZERO
+            );
+        }
+        // queued behind each other a tenth of a second apart
+        let first = limiter.reserve("DescribeFirewallRules", now);
+        let second = limiter.reserve("DescribeFirewallRules", now);
+        assert!((first.as_secs_f64() - 0.1).abs() < 1e-6, "{first:?}");
+        assert!((second.as_secs_f64() - 0.2).abs() < 1e-6, "{second:?}");
+        // refilled after the queue has been served
+        let later = now + Duration::from_millis(1500);
+        assert_eq!(
+            limiter.reserve("DescribeFirewallRules", later),
+            Duration::ZERO
+        );
+
+        assert_eq!(limiter.reserve("CreateFirewallRules", now), Duration::ZERO);
+        assert_eq!(limiter.reserve("CreateFirewallRules", now), Duration::ZERO);
+        let wait = limiter.reserve("CreateFirewallRules", now);
+        assert!((wait.as_secs_f64() - 0.5).abs() < 1e-6, "{wait:?}");
+
+        for _ in 0..100 {
+            assert_eq!(limiter.reserve("DescribeInstances", now), Duration::ZERO);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::QCloudError;
+
+/// When and how often a failed API call is tried again, e.g. in a profile
+///
+/// ```toml
+/// [profiles.home.retry]
+/// max_attempts = 5
+/// base_delay_ms = 1000
+/// retryable_codes = ["RequestLimitExceeded", "InternalError", "ResourceUnavailable"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Attempts of a call including the first one, 1 disables retries
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further retry
+    #[serde(default = "default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    /// Upper bound of the delay between two attempts
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Error codes worth another attempt. A code also covers its sub codes, e.g.
+    /// `RequestLimitExceeded` covers `RequestLimitExceeded.UinLimitExceeded`. Transport errors
+    /// such as a reset connection are always retried
+    #[serde(default = "default_retryable_codes")]
+    pub retryable_codes: Vec<String>,
+}
+
+fn default_max_attempts() -> u32 {
+    4
+}
+
+fn default_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_max_delay_ms() -> u64 {
+    10_000
+}
+
+fn default_retryable_codes() -> Vec<String> {
+    vec!["RequestLimitExceeded".to_string(), "InternalError".to_string()]
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            base_delay_ms: default_base_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+            retryable_codes: default_retryable_codes(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy trying every call once
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the call failing with `err` may succeed when tried again
+    pub fn is_retryable(&self, err: &QCloudError) -> bool {
+        match err {
+            QCloudError::Transport(e) => !e.is_builder(),
+            QCloudError::Api { code, .. } => self.retryable_codes.iter().any(|retryable| {
+                code == retryable
+                    || code
+                        .strip_prefix(retryable.as_str())
+                        .is_some_and(|sub| sub.starts_with('.'))
+            }),
+            _ => false,
+        }
+    }
+
+    /// Delay before retry number `retry` (0 for the first retry): the exponential backoff
+    /// capped at `max_delay_ms`, of which a random part up to a half is taken off, so clients
+    /// failing together don't retry together
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay_ms
+            .saturating_mul(1u64.checked_shl(retry).unwrap_or(u64::MAX))
+            .min(self.max_delay_ms);
+        let jitter = (backoff as f64 * rand::random::<f64>() / 2.0) as u64;
+        Duration::from_millis(backoff - jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(code: &str) -> QCloudError {
+        QCloudError::Api {
+            code: code.to_string(),
+            message: String::new(),
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn test_retryable_codes_cover_sub_codes() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_retryable(&api_error("RequestLimitExceeded")));
+        assert!(policy.is_retryable(&api_error("RequestLimitExceeded.UinLimitExceeded")));
+        assert!(policy.is_retryable(&api_error("InternalError")));
+        assert!(!policy.is_retryable(&api_error("InternalErrorX")));
+        assert!(!policy.is_retryable(&api_error("AuthFailure.SignatureExpire")));
+        assert!(!policy.is_retryable(&QCloudError::Conflict(String::new())));
+    }
+
+    #[test]
+    fn test_delay_grows_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy::default();
+        for retry in 0..3 {
+            let backoff = 500 << retry;
+            let delay = policy.delay(retry).as_millis() as u64;
+            assert!(delay >= backoff / 2 && delay <= backoff, "{retry}: {delay}");
+        }
+        assert!(policy.delay(10).as_millis() <= 10_000);
+        assert!(policy.delay(100).as_millis() <= 10_000);
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    request::FirewallRule, AuditEvent, FirewallPlan, IpDetection, IpFamily, PublicIps, QCloudError,
+};
+
+/// Version of the state file written by this build. Files of a newer version are refused
+pub const STATE_VERSION: u32 = 1;
+
+/// What has been applied to the firewall of each instance, kept across runs and reboots
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    pub version: u32,
+    #[serde(default)]
+    pub instances: BTreeMap<String, InstanceState>,
+    /// Ips of the legacy text files, taken as applied to instances without state of their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migrated: Option<PublicIps>,
+}
+
+/// Last successful reconciliation of an instance
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstanceState {
+    /// Public ips the firewall was last reconciled with, per family
+    #[serde(default)]
+    pub ips: PublicIps,
+    /// Fingerprints of the rules the template produced for `ips`, see [`rule_fingerprint`]
+    #[serde(default)]
+    pub rules: Vec<String>,
+    /// `FirewallVersion` of the instance after the reconciliation
+    pub firewall_version: Option<i64>,
+    /// When rules were last added or removed
+    pub applied_at: Option<DateTime<Utc>>,
+    /// When the firewall was last found matching the template
+    pub reconciled_at: Option<DateTime<Utc>>,
+    /// Public ips last detected, per family, whether applying them succeeded or not
+    #[serde(default)]
+    pub detected: PublicIps,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            version: STATE_VERSION,
+            instances: BTreeMap::new(),
+            migrated: None,
+        }
+    }
+}
+
+impl State {
+    /// Ips last applied to `instance_id`, or those of the legacy text files if the instance
+    /// has no state yet
+    pub fn applied_ips(&self, instance_id: &str) -> Option<&PublicIps> {
+        self.instances
+            .get(instance_id)
+            .map(|instance| &instance.ips)
+            .filter(|ips| **ips != PublicIps::default())
+            .or(self.migrated.as_ref())
+    }
+
+    /// Whether any ip of `ips` differs from the one last applied to `instance_id`. Families
+    /// missing in `ips` are ignored
+    pub fn ip_changed(&self, instance_id: &str, ips: &PublicIps) -> bool {
+        let applied = self.applied_ips(instance_id);
+        [IpFamily::Ipv4, IpFamily::Ipv6].into_iter().any(|family| {
+            ips.get(family)
+                .is_some_and(|ip| applied.and_then(|applied| applied.get(family)) != Some(ip))
+        })
+    }
+
+    /// Note the ips of `detections` as detected for `instance_id`, returning audit events for
+    /// those which differ from the ones detected before, or last applied if none was noted yet.
+    /// Noted before applying, so a change is recorded once even if applying it fails
+    pub fn note_detections(
+        &mut self,
+        instance_id: &str,
+        detections: &[IpDetection],
+    ) -> Vec<AuditEvent> {
+        let applied = self.applied_ips(instance_id).cloned().unwrap_or_default();
+        let instance = self.instances.entry(instance_id.to_string()).or_default();
+        let mut changes = Vec::new();
+        for detection in detections {
+            let Ok(ip) = detection.ip.parse() else {
+                continue;
+            };
+            let family = IpFamily::of(&ip);
+            let previous = instance
+                .detected
+                .get(family)
+                .or_else(|| applied.get(family))
+                .map(str::to_string);
+            if previous.as_deref() == Some(detection.ip.as_str()) {
+                continue;
+            }
+            changes.push(AuditEvent::IpChanged {
+                instance_id: instance_id.to_string(),
+                family,
+                previous,
+                ip: detection.ip.clone(),
+                providers: detection.providers.clone(),
+            });
+            match family {
+                IpFamily::Ipv4 => instance.detected.ipv4 = Some(detection.ip.clone()),
+                IpFamily::Ipv6 => instance.detected.ipv6 = Some(detection.ip.clone()),
+            }
+        }
+        changes
+    }
+
+    /// Record that the firewall of the plan's instance now allows `ips`. The ip of a family
+    /// missing in `ips` is kept
+    pub fn record(&mut self, plan: &FirewallPlan, ips: &PublicIps, firewall_version: Option<i64>) {
+        let now = Utc::now();
+        let instance = self.instances.entry(plan.instance_id.clone()).or_default();
+        if ips.ipv4.is_some() {
+            instance.ips.ipv4 = ips.ipv4.clone();
+        }
+        if ips.ipv6.is_some() {
+            instance.ips.ipv6 = ips.ipv6.clone();
+        }
+        let mut rules: Vec<String> = plan
+            .to_add
+            .iter()
+            .chain(plan.unchanged.iter())
+            .map(rule_fingerprint)
+            .collect();
+        rules.sort();
+        instance.rules = rules;
+        instance.firewall_version = firewall_version;
+        if !plan.is_empty() {
+            instance.applied_at = Some(now);
+        }
+        instance.reconciled_at = Some(now);
+    }
+}
+
+/// Short, stable digest of `rule`, to tell whether the rules in the firewall have changed
+pub fn rule_fingerprint(rule: &FirewallRule) -> String {
+    let json = serde_json::to_string(rule).unwrap_or_default();
+    let hash = Sha256::digest(json.as_bytes());
+    let mut buf = [0u8; 64];
+    let hex = base16ct::lower::encode_str(&hash, &mut buf).unwrap_or_default();
+    hex[..16].to_string()
+}
+
+/// Text file the last ip of `family` was kept in before the state file, `path` for IPv4 and
+/// `path` with `_v6` appended to its name for IPv6
+pub(crate) fn legacy_ip_file(path: &Path, family: IpFamily) -> PathBuf {
+    match family {
+        IpFamily::Ipv4 => path.to_path_buf(),
+        IpFamily::Ipv6 => {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let name = match path.extension() {
+                Some(ext) => format!("{stem}_v6.{}", ext.to_string_lossy()),
+                None => format!("{stem}_v6"),
+            };
+            path.with_file_name(name)
+        }
+    }
+}
+
+/// JSON state file, replaced atomically on every change and locked while in use, so runs
+/// overlapping with the daemon don't lose each other's updates
+pub struct StateStore {
+    path: PathBuf,
+    legacy_path: Option<PathBuf>,
+}
+
+impl StateStore {
+    /// Store at `path`, falling back to STATE_FILE variable and then
+    /// [`StateStore::default_path`]. The ips of the legacy text file at `legacy_path` are
+    /// migrated when there is no state file yet.
+    pub fn new(path: Option<String>, legacy_path: Option<String>) -> Self {
+        let path = path
+            .or_else(|| dotenv::var("STATE_FILE").ok())
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(Self::default_path);
+        Self {
+            path,
+            legacy_path: legacy_path.map(PathBuf::from),
+        }
+    }
+
+    /// Store in the directory of the legacy text file at `legacy_path`, unless STATE_FILE
+    /// variable names another file. For callers of the C API, which pass a path they can write
+    /// to while the default path may be read-only, e.g. on OpenWrt
+    pub fn beside(legacy_path: String) -> Self {
+        let path = dotenv::var("STATE_FILE")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .unwrap_or_else(|| {
+                Path::new(&legacy_path)
+                    .with_file_name("update_qcloud_firewall_state.json")
+                    .display()
+                    .to_string()
+            });
+        Self::new(Some(path), Some(legacy_path))
+    }
+
+    /// `$XDG_STATE_HOME/update_qcloud_firewall/state.json`, falling back to
+    /// `~/.local/state/update_qcloud_firewall/state.json`, which unlike the temporary
+    /// directory survive a reboot
+    pub fn default_path() -> PathBuf {
+        std::env::var_os("XDG_STATE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state"))
+            })
+            .map(|dir| dir.join("update_qcloud_firewall").join("state.json"))
+            .unwrap_or_else(|| std::env::temp_dir().join("update_qcloud_firewall_state.json"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the state, migrating the legacy text file if there is no state file yet
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the state file can't be read, isn't valid or was
+    /// written by a newer version.
+    pub fn load(&self) -> Result<State, QCloudError> {
+        let _lock = self.lock()?;
+        self.read()
+    }
+
+    /// Change the state with `f` and write it back, holding the lock in between
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the state file can't be read or written.
+    pub fn update<T>(&self, f: impl FnOnce(&mut State) -> T) -> Result<T, QCloudError> {
+        let _lock = self.lock()?;
+        let mut state = self.read()?;
+        let result = f(&mut state);
+        self.write(&state)?;
+        Ok(result)
+    }
+
+    fn read(&self) -> Result<State, QCloudError> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return self.migrate(),
+            Err(e) => return Err(e.into()),
+        };
+        let state: State = serde_json::from_str(&content).map_err(|e| {
+            QCloudError::StateIo(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {e}", self.path.display()),
+            ))
+        })?;
+        if state.version > STATE_VERSION {
+            return Err(QCloudError::StateIo(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has version {}, newer than the supported version {STATE_VERSION}",
+                    self.path.display(),
+                    state.version
+                ),
+            )));
+        }
+        Ok(state)
+    }
+
+    /// State holding the ips of the legacy text files, written right away so the text files
+    /// are only read once
+    fn migrate(&self) -> Result<State, QCloudError> {
+        let mut state = State::default();
+        let Some(legacy_path) = &self.legacy_path else {
+            return Ok(state);
+        };
+        let mut ips = PublicIps::default();
+        for family in [IpFamily::Ipv4, IpFamily::Ipv6] {
+            let ip = match fs::read_to_string(legacy_ip_file(legacy_path, family)) {
+                Ok(ip) => ip.trim().to_string(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            match family {
+                IpFamily::Ipv4 if !ip.is_empty() => ips.ipv4 = Some(ip),
+                IpFamily::Ipv6 if !ip.is_empty() => ips.ipv6 = Some(ip),
+                _ => {}
+            }
+        }
+        if ips != PublicIps::default() {
+            state.migrated = Some(ips);
+            self.write(&state)?;
+            eprintln!(
+                "Migrated {} into {}",
+                legacy_path.display(),
+                self.path.display()
+            );
+        }
+        Ok(state)
+    }
+
+    /// Write to a temporary file next to the state file and rename it over the state file, so
+    /// a crash never leaves a truncated state behind
+    fn write(&self, state: &State) -> Result<(), QCloudError> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = self.sibling("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(serde_json::to_string_pretty(state)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Exclusive lock on a lock file next to the state file, released when dropped. The state
+    /// file itself is replaced on every write, so it can't carry the lock
+    fn lock(&self) -> Result<File, QCloudError> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.sibling("lock"))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(file)
+    }
+
+    /// `<state file>.<ext>`
+    fn sibling(&self, ext: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(ext);
+        self.path.with_file_name(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::rule;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "update_qcloud_firewall_{name}_{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn plan(instance_id: &str, cidr: &str) -> FirewallPlan {
+        FirewallPlan {
+            instance_id: instance_id.to_string(),
+            to_add: vec![rule("ssh", cidr)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_state_survives_a_new_store() {
+        let dir = temp_path("state");
+        let path = dir.join("state.json").display().to_string();
+        let store = StateStore::new(Some(path.clone()), None);
+        assert_eq!(store.load().unwrap(), State::default());
+
+        let ips = PublicIps::from("1.1.1.1");
+        store
+            .update(|state| state.record(&plan("lhins-a", "1.1.1.1"), &ips, Some(7)))
+            .unwrap();
+        // the temporary file has been renamed over the state file
+        assert!(!dir.join("state.json.tmp").exists());
+
+        let state = StateStore::new(Some(path), None).load().unwrap();
+        let instance = &state.instances["lhins-a"];
+        assert_eq!(instance.ips, ips);
+        assert_eq!(instance.firewall_version, Some(7));
+        assert_eq!(instance.rules.len(), 1);
+        assert!(instance.applied_at.is_some());
+        assert!(!state.ip_changed("lhins-a", &ips));
+        assert!(state.ip_changed("lhins-a", &PublicIps::from("2.2.2.2")));
+        assert!(state.ip_changed("lhins-b", &ips));
+        let detections = [
+            IpDetection {
+                ip: "1.1.1.1".to_string(),
+                providers: vec!["a".to_string()],
+            },
+            IpDetection {
+                ip: "2400:3200::1".to_string(),
+                providers: vec!["b".to_string()],
+            },
+        ];
+        // only the IPv6 address is new, and noted once even though it isn't applied
+        let mut state = state;
+        let changes = state.note_detections("lhins-a", &detections);
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(
+            &changes[0],
+            AuditEvent::IpChanged {
+                family: IpFamily::Ipv6,
+                previous: None,
+                ..
+            }
+        ));
+        assert!(state.note_detections("lhins-a", &detections).is_empty());
+        assert!(state.ip_changed("lhins-a", &PublicIps::from("2400:3200::1")));
+        // an instance noted before it is applied still falls back to the migrated ips
+        state.migrated = Some(PublicIps::from("1.1.1.1"));
+        state.note_detections("lhins-c", &detections[1..]);
+        assert!(!state.ip_changed("lhins-c", &PublicIps::from("1.1.1.1")));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_legacy_ip_files_are_migrated() {
+        let dir = temp_path("migration");
+        fs::create_dir_all(&dir).unwrap();
+        let legacy = dir.join("update_qcloud_firewall_ip.txt");
+        fs::write(&legacy, "1.1.1.1\n").unwrap();
+        fs::write(dir.join("update_qcloud_firewall_ip_v6.txt"), "2400:3200::1").unwrap();
+
+        let store = StateStore::new(
+            Some(dir.join("state.json").display().to_string()),
+            Some(legacy.display().to_string()),
+        );
+        let state = store.load().unwrap();
+        let ips = PublicIps {
+            ipv4: Some("1.1.1.1".to_string()),
+            ipv6: Some("2400:3200::1".to_string()),
+        };
+        assert_eq!(state.migrated.as_ref(), Some(&ips));
+        assert!(dir.join("state.json").exists());
+        assert!(!state.ip_changed("lhins-a", &ips));
+        assert!(state.ip_changed("lhins-a", &PublicIps::from("2.2.2.2")));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_newer_state_version_is_refused() {
+        let dir = temp_path("version");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        fs::write(&path, r#"{"version": 999, "instances": {}}"#).unwrap();
+        let store = StateStore::new(Some(path.display().to_string()), None);
+        assert!(matches!(store.load(), Err(QCloudError::StateIo(_))));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,129 @@
+//! Helpers shared by unit tests
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    request::{CreateDeleteFirewallRulesRequest, FirewallRule},
+    response::FirewallRuleSet,
+};
+
+/// Template rule accepting TCP 443 from `cidr`
+pub fn rule(desc: &str, cidr: &str) -> FirewallRule {
+    FirewallRule {
+        protocol: Some("TCP".to_string()),
+        port: Some("443".to_string()),
+        cidr_block: Some(cidr.to_string()),
+        ipv6_cidr_block: None,
+        action: Some("ACCEPT".to_string()),
+        firewall_rule_description: Some(desc.to_string()),
+    }
+}
+
+/// Template with a [`rule`] per description, its cidr to be replaced by the public ip
+pub fn tpl(descs: &[&str]) -> CreateDeleteFirewallRulesRequest {
+    CreateDeleteFirewallRulesRequest {
+        instance_id: "lhins-3jq1gki4".to_string(),
+        firewall_rules: descs.iter().map(|desc| rule(desc, "127.0.0.1")).collect(),
+    }
+}
+
+/// Live rule accepting TCP 443 from `cidr`, with the protocol in lower case as the console
+/// may return it
+pub fn live(desc: &str, cidr: &str) -> FirewallRuleSet {
+    FirewallRuleSet {
+        app_type: Some("HTTPS".to_string()),
+        protocol: "tcp".to_string(),
+        port: Some("443".to_string()),
+        cidr_block: cidr.to_string(),
+        ipv6_cidr_block: None,
+        action: Some("ACCEPT".to_string()),
+        firewall_rule_description: Some(desc.to_string()),
+    }
+}
+
+/// DescribeFirewallRules response with `rules` out of `total` rules
+pub fn describe_response_of(rules: &[FirewallRuleSet], total: usize) -> String {
+    serde_json::json!({
+        "Response": {
+            "TotalCount": total,
+            "FirewallRuleSet": rules,
+            "RequestId": "req",
+        }
+    })
+    .to_string()
+}
+
+/// DescribeFirewallRules response with a [`live`] rule per description and cidr
+pub fn describe_response(rules: &[(&str, &str)]) -> String {
+    let rules: Vec<FirewallRuleSet> = rules.iter().map(|(desc, cidr)| live(desc, cidr)).collect();
+    describe_response_of(&rules, rules.len())
+}
+
+/// Page of DescribeFirewallRules starting at `offset`, with rules `rule <i>` on port `<i>`
+pub fn describe_page(offset: usize, count: usize, total: usize) -> String {
+    let rules: Vec<FirewallRuleSet> = (offset..offset + count)
+        .map(|i| FirewallRuleSet {
+            port: Some(i.to_string()),
+            ..live(&format!("rule {i}"), "1.2.3.4")
+        })
+        .collect();
+    describe_response_of(&rules, total)
+}
+
+/// Serve canned `responses` on a local port, one per connection, and give back the raw
+/// requests received.
+pub async fn stand_in_server(
+    responses: Vec<String>,
+) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for body in responses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            loop {
+                let mut chunk = [0u8; 4096];
+                let n = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                let text = String::from_utf8_lossy(&buf).to_string();
+                if let Some(pos) = text.find("\r\n\r\n") {
+                    let content_length = text[..pos]
+                        .lines()
+                        .find_map(|l| {
+                            l.to_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if buf.len() >= pos + 4 + content_length {
+                        break;
+                    }
+                }
+                if n == 0 {
+                    break;
+                }
+            }
+            requests.push(String::from_utf8_lossy(&buf).to_string());
+            let reply = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(reply.as_bytes()).await.unwrap();
+        }
+        requests
+    });
+    (base_url, handle)
+}
+
+/// `X-TC-Action` header of a raw request captured by `stand_in_server`
+pub fn request_action(raw: &str) -> String {
+    raw.lines()
+        .find_map(|l| {
+            l.to_lowercase()
+                .starts_with("x-tc-action:")
+                .then(|| l["x-tc-action:".len()..].trim().to_string())
+        })
+        .unwrap_or_default()
+}
@@ -1,13 +1,23 @@
 use std::ffi::{c_char, CStr, CString};
-use std::path::Path;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 
 use tokio::fs;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use crate::firewall_filter::FirewallRuleFilter;
+use crate::firewall_plan::{
+    export_template, firewall_rule_of, rule_matches, templated_rules, FirewallPlan,
+};
 use crate::request::{CreateDeleteFirewallRulesRequest, FirewallRule};
-use crate::rust_struct::{IpInfo, SetBIpInfo};
-use crate::{QCloudError, QCloudWebClient};
+use crate::response::FirewallRuleSet;
+use crate::rust_struct::IpInfo;
+use crate::state::legacy_ip_file;
+use crate::{
+    AuditEvent, AuditLog, Config, IpDetection, IpDetector, IpFamily, Profile, PublicIps,
+    QCloudError, QCloudWebClient, RetryPolicy, StateStore, DEFAULT_ENDPOINT, DEFAULT_REGION,
+};
 
 //Lazy static
 lazy_static! {
@@ -22,31 +32,28 @@ lazy_static! {
         .danger_accept_invalid_certs(true).build().unwrap();
 }
 
-/// .
-///
-/// # Panics
-///
-/// Panics if .
+/// Copy a C string into a String, replacing invalid UTF-8 sequences.
 ///
 /// # Safety
 ///
-/// .
+/// `c_str` must be a valid, nul terminated C string.
 pub unsafe fn cchar_to_string(c_str: *mut c_char) -> String {
     let cstr = unsafe { CStr::from_ptr(c_str) };
-    cstr.to_str().unwrap().to_string()
+    cstr.to_string_lossy().to_string()
 }
 // const IP_TXT: &str = "ip.txt";
 // const IPHONE_TPL: &str = "iphone.tpl";
 
+/// Convert reference of str to CString. Interior nul bytes, which C can't represent, are
+/// dropped.
+pub fn str_to_cstring(p_str: &str) -> CString {
+    CString::new(p_str.replace('\0', "")).unwrap_or_default()
+}
+
 /// Convert reference of str to mutable c_char pointer.
 /// Warning: Must release String from Rust otherwise it will lead to memory leak.
-///
-/// # Panics
-///
-/// Panics if .
 pub fn str_to_c_char_ptr(p_str: &str) -> *mut c_char {
-    let cstr = CString::new(p_str).unwrap();
-    cstr.into_raw()
+    str_to_cstring(p_str).into_raw()
 }
 
 //Local callback for loading
@@ -116,12 +123,25 @@ pub enum PayloadType {
     LCRD,
 }
 
+/// Configuration file and profile of the target a [`WebClient`] was created for, opaque to C
+pub struct TargetConfig {
+    config: Config,
+    profile: Profile,
+}
+
 #[repr(C)]
 pub struct WebClient {
     tmp_file_path: *mut c_char,
     instance_id: *mut c_char,
     token_id: *mut c_char,
     token_key: *mut c_char,
+    /// Empty to fall back to REGION variable or the default region
+    region: *mut c_char,
+    /// Empty to fall back to ENDPOINT variable or the default endpoint
+    endpoint: *mut c_char,
+    /// Null unless created from a configuration file, whose ip detection, retry policy, rate
+    /// limits, state file and audit log are then used
+    target_config: Option<Box<TargetConfig>>,
 }
 
 impl Drop for WebClient {
@@ -131,6 +151,8 @@ impl Drop for WebClient {
             drop(CString::from_raw(self.instance_id));
             drop(CString::from_raw(self.token_id));
             drop(CString::from_raw(self.token_key));
+            drop(CString::from_raw(self.region));
+            drop(CString::from_raw(self.endpoint));
         }
     }
 }
@@ -142,25 +164,57 @@ impl WebClient {
         instance_id: &str,
         token_id: &str,
         token_key: &str,
+        region: &str,
+        endpoint: &str,
     ) -> WebClient {
         let ptr_tmp_file_path = str_to_c_char_ptr(tmp_file_path);
         let ptr_instance_id = str_to_c_char_ptr(instance_id);
         let ptr_token_id = str_to_c_char_ptr(token_id);
         let ptr_token_key = str_to_c_char_ptr(token_key);
+        let ptr_region = str_to_c_char_ptr(region);
+        let ptr_endpoint = str_to_c_char_ptr(endpoint);
         WebClient {
             tmp_file_path: ptr_tmp_file_path,
             instance_id: ptr_instance_id,
             token_id: ptr_token_id,
             token_key: ptr_token_key,
+            region: ptr_region,
+            endpoint: ptr_endpoint,
+            target_config: None,
         }
     }
 
+    /// Create for `target` of the configuration file at `config_path`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the configuration can't be loaded or the target
+    /// or its profile doesn't exist.
+    pub fn from_config(
+        tmp_file_path: &str,
+        config_path: &Path,
+        target: &str,
+    ) -> Result<WebClient, QCloudError> {
+        let config = Config::load(config_path)?;
+        let target = config.target(target)?;
+        let profile = config.target_profile(target)?;
+        let mut client = WebClient::new(
+            tmp_file_path,
+            &target.instance_id,
+            &profile.secret_id,
+            &profile.secret_key,
+            profile.region.as_deref().unwrap_or_default(),
+            profile.endpoint.as_deref().unwrap_or_default(),
+        );
+        client.target_config = Some(Box::new(TargetConfig { config, profile }));
+        Ok(client)
+    }
+
     /// Wrap tokio async function to call reqwest async function and give back the result or error
     /// through callback function
     pub fn getIpConfig(&self, mut callback: Box<dyn WebApiCallback + Send>) {
         (*RUN_TIME).block_on(async move {
-            let c_str = unsafe { CStr::from_ptr(self.tmp_file_path) };
-            let tmp_file_path = c_str.to_str().unwrap().to_string();
+            let tmp_file_path = unsafe { cchar_to_string(self.tmp_file_path) };
             let ip_tools = IpTools::new(tmp_file_path);
             let res = ip_tools.get_ip_config().await;
             match res {
@@ -188,56 +242,99 @@ impl WebClient {
             let instance_id = unsafe { cchar_to_string(self.instance_id) };
             let token_id = unsafe { cchar_to_string(self.token_id) };
             let token_key = unsafe { cchar_to_string(self.token_key) };
+            let region = unsafe { cchar_to_string(self.region) };
+            let endpoint = unsafe { cchar_to_string(self.endpoint) };
 
-            let ip_tools = IpTools::new(tmp_file_path);
-            let ip = ip_tools.get_china_ip_address().await.unwrap();
-            let res = ip_tools.check_ip_changed(&ip).await.unwrap();
-            // if ip not changed, exit immediately
-            if !res {
-                return;
+            let config = self.target_config.as_ref().map(|target| &target.config);
+            if let Some(config) = config {
+                config.rate_limits.clone().apply_globally();
+            }
+            // the state file next to it replaces the temporary ip file, which is migrated once
+            let store = match config.and_then(|config| config.state_file.clone()) {
+                Some(state_file) => StateStore::new(Some(state_file), Some(tmp_file_path.clone())),
+                None => StateStore::beside(tmp_file_path.clone()),
+            };
+            let ip_tools = match config {
+                Some(config) => IpTools::with_detector(tmp_file_path, config.ip_detection.clone()),
+                None => IpTools::new(tmp_file_path),
+            };
+            let detections = match ip_tools.detect_public_ips().await {
+                Ok(detections) => detections,
+                Err(err) => {
+                    callback.onError(&format!("Failed to get public ip. Err: {}", err));
+                    return;
+                }
+            };
+            let ips = PublicIps::from_detections(&detections);
+            match store.load() {
+                // if ip not changed, exit immediately
+                Ok(state) if !state.ip_changed(&instance_id, &ips) => return,
+                Ok(_) => {}
+                Err(err) => {
+                    callback.onError(&format!("Failed to check ip change. Err: {}", err));
+                    return;
+                }
+            }
+            let qcloud_tool = match &self.target_config {
+                Some(target) => Ok(QCloudTool::from_profile(&target.profile)),
+                None => QCloudTool::new(
+                    Some(token_id),
+                    Some(token_key),
+                    Some(region).filter(|v| !v.is_empty()),
+                    Some(endpoint).filter(|v| !v.is_empty()),
+                ),
+            };
+            let audit_log = AuditLog::new(config.and_then(|config| config.audit_log.clone()));
+            let qcloud_tool = match qcloud_tool {
+                Ok(qcloud_tool) => qcloud_tool.with_audit_log(audit_log),
+                Err(err) => {
+                    callback.onError(&format!("Failed to create qcloud tool. Err: {}", err));
+                    return;
+                }
+            };
+            // the change is audited before applying it, so it is recorded even if that fails
+            match store.update(|state| state.note_detections(&instance_id, &detections)) {
+                Ok(changes) => qcloud_tool.audit_all(changes),
+                Err(err) => {
+                    callback.onError(&format!("Failed to save state. Err: {}", err));
+                    return;
+                }
             }
-            let qcloud_tool = QCloudTool::new(Some(token_id), Some(token_key));
             // let request_payload: String = match self.payload_type {
             //     PayloadType::IPHONE => IPHONE11_PAYLOAD_TPL.to_string(),
             //     PayloadType::PDRD => todo!(),
             //     PayloadType::LCRD => todo!(),
             // };
 
-            // we remove existing firewall rules.
             let request: CreateDeleteFirewallRulesRequest =
-                serde_json::from_str(request_payload).unwrap();
-            let res = qcloud_tool
-                .remove_firewall_rules(&instance_id, &request)
-                .await;
-            match res {
-                Ok(_root) => {
-                    //print response
-                    //println!("Response: {:#?}", root.results);
-                    callback.onLoad("Sucessfully remove firewall policy!");
-                }
-                Err(err) => {
-                    let error = format!("Failed to remove firewall policy. Err: {}", err);
-                    // println!("Error: {}", error);
-                    callback.onError(error.as_str());
-                    return;
-                }
-            }
+                match serde_json::from_str(request_payload) {
+                    Ok(request) => request,
+                    Err(err) => {
+                        callback.onError(&format!("Invalid payload. Err: {}", err));
+                        return;
+                    }
+                };
 
-            // we create new firewall rules with new public ip in cidr field
-            let res = qcloud_tool
-                .create_firewall_rules(&instance_id, &request, &ip)
-                .await;
+            // we create new firewall rules with new public ip in cidr field before removing
+            // the stale ones, so we are never locked out
+            let res = match qcloud_tool
+                .plan_firewall_rules(&instance_id, &request, &ips)
+                .await
+            {
+                Ok(plan) => qcloud_tool.apply_firewall_plan(&plan).await.map(|_| plan),
+                Err(err) => Err(err),
+            };
             match res {
-                Ok(_root) => {
-                    //print response
-                    //println!("Response: {:#?}", root.results);
-                    callback.onLoad("Sucessfully create firewall policy!");
-                    // if ip changes, we need to recreate firewall and save the ip into temp file
-                    ip_tools.save_ip_into_file(&ip).await.unwrap();
+                Ok(plan) => {
+                    callback.onLoad("Sucessfully recreate firewall policy!");
+                    // if ip changes, we need to recreate firewall and record the ip in the state
+                    let version = qcloud_tool.firewall_version(&instance_id).await.ok().flatten();
+                    if let Err(err) = store.update(|state| state.record(&plan, &ips, version)) {
+                        callback.onError(&format!("Failed to save state. Err: {}", err))
+                    }
                 }
                 Err(err) => {
-                    let error = format!("Failed to create firewall policy. Err: {}", err);
-                    // println!("Error: {}", error);
+                    let error = format!("Failed to recreate firewall policy. Err: {}", err);
                     callback.onError(error.as_str())
                 }
             }
@@ -245,45 +342,77 @@ impl WebClient {
     }
 }
 
+fn ip_detection_error(e: reqwest::Error) -> QCloudError {
+    QCloudError::IpDetection(e.to_string())
+}
+
 pub struct IpTools {
     tmp_file_path: String,
+    detector: IpDetector,
 }
 
 impl IpTools {
+    /// Detect the public ip with the built-in providers
     pub fn new(tmp_file_path: String) -> Self {
-        Self { tmp_file_path }
+        Self::with_detector(tmp_file_path, IpDetector::default())
+    }
+
+    pub fn with_detector(tmp_file_path: String, detector: IpDetector) -> Self {
+        Self {
+            tmp_file_path,
+            detector,
+        }
     }
 
     /// Actual calling reqwest::get and convert json response body to a struct
-    pub async fn get_ip_config(&self) -> Result<IpInfo, Box<dyn std::error::Error>> {
+    pub async fn get_ip_config(&self) -> Result<IpInfo, QCloudError> {
         let ip_config: IpInfo = HTTP_CLIENT
             .get("http://ifconfig.co/json")
             .send()
-            .await?
+            .await
+            .map_err(ip_detection_error)?
             .json()
-            .await?;
+            .await
+            .map_err(ip_detection_error)?;
         Ok(ip_config)
     }
 
-    pub async fn get_china_ip_address(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let ip_config: SetBIpInfo = HTTP_CLIENT
-            .get("https://setb.cn/ip.json")
-            .send()
-            .await?
-            .json()
-            .await?;
-        Ok(ip_config.publicip)
+    /// Public ip of `family` agreed on by the quorum of providers, with the providers that
+    /// reported it
+    pub async fn detect_public_ip(&self, family: IpFamily) -> Result<IpDetection, QCloudError> {
+        self.detector.detect(family).await
+    }
+
+    /// Public ip of each configured family, detected independently
+    pub async fn detect_public_ips(&self) -> Result<Vec<IpDetection>, QCloudError> {
+        self.detector.detect_all().await
+    }
+
+    /// Public IPv4 address agreed on by the quorum of providers
+    pub async fn get_china_ip_address(&self) -> Result<String, QCloudError> {
+        Ok(self.detect_public_ip(IpFamily::Ipv4).await?.ip)
+    }
+
+    /// File caching the last ip of the family of `public_ip`. IPv6 addresses are kept next to
+    /// the IPv4 file, with `_v6` appended to its name.
+    fn cache_file(&self, public_ip: &str) -> PathBuf {
+        let family = match public_ip.trim().parse::<IpAddr>() {
+            Ok(ip) => IpFamily::of(&ip),
+            Err(_) => IpFamily::Ipv4,
+        };
+        legacy_ip_file(Path::new(&self.tmp_file_path), family)
     }
 
     pub async fn check_ip_changed(
         &self,
         public_ip: &str,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        if !Path::new(&self.tmp_file_path).exists() {
+    ) -> Result<bool, QCloudError> {
+        let cache_file = self.cache_file(public_ip);
+        if !cache_file.exists() {
             // file not exist
             return Ok(true);
         }
-        let mut f = File::open(&self.tmp_file_path).await.unwrap();
+        let mut f = File::open(&cache_file).await?;
         let mut buffer = Vec::new();
 
         // read the whole file
@@ -301,40 +430,162 @@ impl IpTools {
     pub async fn save_ip_into_file(
         &self,
         public_ip: &str,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        if Path::new(&self.tmp_file_path).exists() {
-            fs::remove_file(&self.tmp_file_path).await.unwrap_or(());
+    ) -> Result<bool, QCloudError> {
+        let cache_file = self.cache_file(public_ip);
+        if cache_file.exists() {
+            fs::remove_file(&cache_file).await.unwrap_or(());
         }
-        let mut file = File::create(&self.tmp_file_path).await?;
+        let mut file = File::create(&cache_file).await?;
 
         file.write_all(public_ip.trim().as_bytes()).await?;
         Ok(true)
     }
 }
 
+fn env_credential(name: &str) -> Result<String, QCloudError> {
+    dotenv::var(name)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| QCloudError::Config(format!("{name} is not set")))
+}
+
+#[derive(Clone)]
 pub struct QCloudTool {
     secret_id: String,
     secret_key: String,
+    region: String,
+    endpoint: String,
+    audit_log: Option<AuditLog>,
+    retry: RetryPolicy,
 }
 
 impl QCloudTool {
-    pub fn new(secret_id: Option<String>, secret_key: Option<String>) -> Self {
-        if secret_id.is_none() | secret_key.is_none() {
-            return Self {
-                secret_id: dotenv::var("SECRETID").unwrap(),
-                secret_key: dotenv::var("SECRETKEY").unwrap(),
-            };
-        }
+    /// Missing credentials fall back to SECRETID and SECRETKEY variables. Missing region and
+    /// endpoint fall back to REGION and ENDPOINT variables, then to `ap-shanghai` and the
+    /// global Lighthouse endpoint.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a credential is neither given nor set in the
+    /// environment.
+    pub fn new(
+        secret_id: Option<String>,
+        secret_key: Option<String>,
+        region: Option<String>,
+        endpoint: Option<String>,
+    ) -> Result<Self, QCloudError> {
+        let region = region
+            .or_else(|| dotenv::var("REGION").ok().filter(|v| !v.is_empty()))
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+        let endpoint = endpoint
+            .or_else(|| dotenv::var("ENDPOINT").ok().filter(|v| !v.is_empty()))
+            .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string());
+        let secret_id = match secret_id {
+            Some(secret_id) => secret_id,
+            None => env_credential("SECRETID")?,
+        };
+        let secret_key = match secret_key {
+            Some(secret_key) => secret_key,
+            None => env_credential("SECRETKEY")?,
+        };
+        Ok(Self {
+            secret_id,
+            secret_key,
+            region,
+            endpoint,
+            audit_log: None,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// Create from a profile of the configuration file
+    pub fn from_profile(profile: &Profile) -> Self {
         Self {
-            secret_id: secret_id.unwrap(),
-            secret_key: secret_key.unwrap(),
+            secret_id: profile.secret_id.clone(),
+            secret_key: profile.secret_key.clone(),
+            region: profile
+                .region
+                .clone()
+                .unwrap_or_else(|| DEFAULT_REGION.to_string()),
+            endpoint: profile
+                .endpoint
+                .clone()
+                .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string()),
+            audit_log: None,
+            retry: profile.retry.clone(),
         }
     }
+
+    /// Record every create and delete request in `audit_log`
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    fn web_client(&self, instance_id: &str) -> QCloudWebClient {
+        QCloudWebClient::new(
+            self.endpoint.clone(),
+            self.region.clone(),
+            "application/json".to_string(),
+            instance_id.to_string(),
+            self.secret_id.to_string(),
+            self.secret_key.to_string(),
+            "lighthouse".to_string(),
+        )
+        .with_retry_policy(self.retry.clone())
+    }
+
+    /// Create the rules of `payload` and record the request in the audit log
+    async fn create_rules(
+        &self,
+        qcloud_webclient: &QCloudWebClient,
+        payload: &CreateDeleteFirewallRulesRequest,
+    ) -> Result<(), QCloudError> {
+        let request_id = qcloud_webclient.create_firewall_rules(payload).await?;
+        self.audit(AuditEvent::RulesCreated {
+            instance_id: payload.instance_id.clone(),
+            rules: payload.firewall_rules.clone(),
+            request_id,
+        });
+        Ok(())
+    }
+
+    /// Delete the rules of `payload` and record the request in the audit log
+    async fn delete_rules(
+        &self,
+        qcloud_webclient: &QCloudWebClient,
+        payload: &CreateDeleteFirewallRulesRequest,
+    ) -> Result<(), QCloudError> {
+        let request_id = qcloud_webclient.delete_firewall_rules(payload).await?;
+        self.audit(AuditEvent::RulesDeleted {
+            instance_id: payload.instance_id.clone(),
+            rules: payload.firewall_rules.clone(),
+            request_id,
+        });
+        Ok(())
+    }
+
+    /// The request already succeeded, so a failure to record it is only reported
+    fn audit(&self, event: AuditEvent) {
+        self.audit_all(vec![event]);
+    }
+
+    /// Record `events` in the audit log, if any. Failures are only reported
+    pub fn audit_all(&self, events: Vec<AuditEvent>) {
+        if let Some(audit_log) = &self.audit_log {
+            for event in events {
+                if let Err(err) = audit_log.append(event) {
+                    eprintln!("Failed to write audit log {}: {err}", audit_log.path().display());
+                }
+            }
+        }
+    }
+
     pub async fn remove_firewall_rules(
         &self,
         instance_id: &str,
         tpl: &CreateDeleteFirewallRulesRequest,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
+    ) -> Result<bool, QCloudError> {
         // let mut desc_list = vec![];
         let desc_list = tpl
             .firewall_rules
@@ -348,28 +599,14 @@ impl QCloudTool {
         // }
         // println!("{}, {}", self.secret_id, self.secret_key);
 
-        let qcloud_webclient = QCloudWebClient::new(
-            "lighthouse.tencentcloudapi.com".to_string(),
-            "application/json".to_string(),
-            instance_id.to_string(),
-            self.secret_id.to_string(),
-            self.secret_key.to_string(),
-            "lighthouse".to_string(),
-        );
+        let qcloud_webclient = self.web_client(instance_id);
         let result = qcloud_webclient
             .query_firewall_rules_by_description(&desc_list)
             .await?;
 
         // let mut firewall_rules_to_delete = Vec::new();
-        let firewall_rules_to_delete: Vec<FirewallRule> = result.iter().map(|rule| {
-            FirewallRule {
-                protocol: Some(rule.protocol.clone()),
-                port: rule.port.clone(),
-                cidr_block: Some(rule.cidr_block.clone()),
-                action: rule.action.clone(),
-                firewall_rule_description: rule.firewall_rule_description.clone(),
-            }
-        }).collect();
+        let firewall_rules_to_delete: Vec<FirewallRule> =
+            result.iter().map(firewall_rule_of).collect();
 
         // for rule in result.iter() {
         //     firewall_rules_to_delete.push(FirewallRule {
@@ -391,9 +628,7 @@ impl QCloudTool {
             firewall_rules: firewall_rules_to_delete,
         };
 
-        let _result = qcloud_webclient
-            .qcloud_delete_firewall_rules(&request_payload)
-            .await?;
+        self.delete_rules(&qcloud_webclient, &request_payload).await?;
 
         println!(
             "Sucessfully delete {} rules",
@@ -408,8 +643,7 @@ impl QCloudTool {
         instance_id: &str,
         tpl: &CreateDeleteFirewallRulesRequest,
         ip_address: &str,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        let mut tpl_clone = tpl.clone();
+    ) -> Result<bool, QCloudError> {
         // let mut desc_list = vec![];
         let desc_list = tpl.firewall_rules.iter().filter_map(|rule| {
             rule.firewall_rule_description.clone()
@@ -421,37 +655,24 @@ impl QCloudTool {
         // }
         // println!("{}, {}", self.secret_id, self.secret_key);
 
-        let qcloud_webclient = QCloudWebClient::new(
-            "lighthouse.tencentcloudapi.com".to_string(),
-            "application/json".to_string(),
-            instance_id.to_string(),
-            self.secret_id.to_string(),
-            self.secret_key.to_string(),
-            "lighthouse".to_string(),
-        );
+        let qcloud_webclient = self.web_client(instance_id);
         let result = qcloud_webclient
             .query_firewall_rules_by_description(&desc_list)
             .await?;
 
         if !result.is_empty() {
-            return Err(Box::new(QCloudError(
+            return Err(QCloudError::Conflict(
                 "Rule(s) exist(s) in firewall. Please delete at first!".to_owned(),
-            )));
-        }
-
-        // Change cidr with given public ip address
-        for rule in tpl_clone.firewall_rules.iter_mut() {
-            rule.cidr_block = Some(ip_address.to_owned());
+            ));
         }
 
+        // Change cidr of the rules of the address family with given public ip address
         let request_payload = CreateDeleteFirewallRulesRequest {
             instance_id: instance_id.to_string(),
-            firewall_rules: tpl_clone.firewall_rules,
+            firewall_rules: templated_rules(tpl, &PublicIps::from(ip_address)),
         };
 
-        let _result = qcloud_webclient
-            .qcloud_create_firewall_rules(&request_payload)
-            .await?;
+        self.create_rules(&qcloud_webclient, &request_payload).await?;
 
         println!(
             "Sucessfully create {} rules",
@@ -460,6 +681,165 @@ impl QCloudTool {
 
         Ok(true)
     }
+
+    /// Query all firewall rules of `instance_id`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the firewall rules can't be queried.
+    pub async fn list_firewall_rules(
+        &self,
+        instance_id: &str,
+    ) -> Result<Vec<FirewallRuleSet>, QCloudError> {
+        self.web_client(instance_id).query_all_firewall_rules().await
+    }
+
+    /// Export the rules of `instance_id` as a payload template, optionally only the rules whose
+    /// description contains `description`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the firewall rules can't be queried.
+    pub async fn export_firewall_rules_template(
+        &self,
+        instance_id: &str,
+        description: Option<String>,
+    ) -> Result<CreateDeleteFirewallRulesRequest, QCloudError> {
+        let filter = FirewallRuleFilter {
+            description,
+            ..FirewallRuleFilter::default()
+        };
+        let rules = filter.apply(self.list_firewall_rules(instance_id).await?);
+        Ok(export_template(instance_id, &rules))
+    }
+
+    /// Current firewall version of `instance_id`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the firewall can't be queried.
+    pub async fn firewall_version(&self, instance_id: &str) -> Result<Option<i64>, QCloudError> {
+        self.web_client(instance_id).query_firewall_version().await
+    }
+
+    /// Compare `tpl` with its cidr replaced by the public ip of each family against the live
+    /// firewall.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the firewall rules can't be queried.
+    pub async fn plan_firewall_rules(
+        &self,
+        instance_id: &str,
+        tpl: &CreateDeleteFirewallRulesRequest,
+        ips: &PublicIps,
+    ) -> Result<FirewallPlan, QCloudError> {
+        let qcloud_webclient = self.web_client(instance_id);
+        // the plan picks the rules of the template, including those without description
+        let live = qcloud_webclient.query_all_firewall_rules().await?;
+        Ok(FirewallPlan::new(instance_id, tpl, ips, &live))
+    }
+
+    /// Execute `plan` without leaving the firewall without the planned rules.
+    ///
+    /// Rules to add are created first and verified with DescribeFirewallRules, then rules to
+    /// remove are deleted. If creating fails or the created rules can't be verified, the ones
+    /// that made it are deleted again and the rules to remove are left in place.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if any request fails or the created rules are not all
+    /// found afterwards.
+    pub async fn apply_firewall_plan(&self, plan: &FirewallPlan) -> Result<bool, QCloudError> {
+        let qcloud_webclient = self.web_client(&plan.instance_id);
+
+        if !plan.to_add.is_empty() {
+            let request_payload = CreateDeleteFirewallRulesRequest {
+                instance_id: plan.instance_id.clone(),
+                firewall_rules: plan.to_add.clone(),
+            };
+            if let Err(err) = self.create_rules(&qcloud_webclient, &request_payload).await {
+                // part of the batch may have been created before the request failed
+                // rules without description are only found among all rules
+                let rolled_back = match qcloud_webclient.query_all_firewall_rules().await {
+                    Ok(live) => self.roll_back(&qcloud_webclient, plan, &live).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = rolled_back {
+                    eprintln!("Failed to roll back created rules: {e}");
+                }
+                return Err(err);
+            }
+
+            let live = qcloud_webclient.query_all_firewall_rules().await?;
+            let missing = plan
+                .to_add
+                .iter()
+                .filter(|rule| !live.iter().any(|item| rule_matches(rule, item)))
+                .count();
+            if missing > 0 {
+                self.roll_back(&qcloud_webclient, plan, &live).await?;
+                return Err(QCloudError::Conflict(format!(
+                    "{missing} created rule(s) not found in firewall. Rolled back."
+                )));
+            }
+            println!("Sucessfully create {} rules", plan.to_add.len());
+        }
+
+        if !plan.to_remove.is_empty() {
+            let request_payload = CreateDeleteFirewallRulesRequest {
+                instance_id: plan.instance_id.clone(),
+                firewall_rules: plan.to_remove.clone(),
+            };
+            self.delete_rules(&qcloud_webclient, &request_payload).await?;
+            println!("Sucessfully delete {} rules", plan.to_remove.len());
+        }
+
+        Ok(true)
+    }
+
+    /// Delete the rules to add of `plan` found in `live` again. They were all missing when the
+    /// plan was made, so those found have been created since; the rules to remove still let us
+    /// in
+    async fn roll_back(
+        &self,
+        qcloud_webclient: &QCloudWebClient,
+        plan: &FirewallPlan,
+        live: &[FirewallRuleSet],
+    ) -> Result<(), QCloudError> {
+        let created: Vec<FirewallRule> = plan
+            .to_add
+            .iter()
+            .filter(|rule| live.iter().any(|item| rule_matches(rule, item)))
+            .cloned()
+            .collect();
+        if !created.is_empty() {
+            let request_payload = CreateDeleteFirewallRulesRequest {
+                instance_id: plan.instance_id.clone(),
+                firewall_rules: created,
+            };
+            self.delete_rules(qcloud_webclient, &request_payload).await?;
+        }
+        Ok(())
+    }
+
+    /// Replace the rules of `tpl` with rules allowing `ips` without leaving the
+    /// firewall without them. Rules already in place are left untouched, so nothing is sent
+    /// when the firewall matches.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if any request fails or the created rules are not all
+    /// found afterwards.
+    pub async fn reconcile_firewall_rules(
+        &self,
+        instance_id: &str,
+        tpl: &CreateDeleteFirewallRulesRequest,
+        ips: &PublicIps,
+    ) -> Result<bool, QCloudError> {
+        let plan = self.plan_firewall_rules(instance_id, tpl, ips).await?;
+        self.apply_firewall_plan(&plan).await
+    }
 }
 
 #[cfg(test)]
@@ -467,6 +847,7 @@ mod tests {
     // use crate::IPHONE11_PAYLOAD_TPL;
 
     use super::*;
+    use crate::test_utils::{describe_response, request_action, stand_in_server, tpl};
 
     #[tokio::test]
     async fn test_save_ip_into_file() {
@@ -479,6 +860,23 @@ mod tests {
         assert!(ip_tools.save_ip_into_file("127.0.0.1").await.unwrap());
     }
 
+    #[tokio::test]
+    async fn test_ip_families_are_cached_separately() {
+        let tmp_file_path = std::env::temp_dir()
+            .join("update_qcloud_firewall_families_ip.txt")
+            .display()
+            .to_string();
+        let ip_tools = IpTools::new(tmp_file_path);
+        ip_tools.save_ip_into_file("1.1.1.1").await.unwrap();
+        ip_tools.save_ip_into_file("2400:3200::1").await.unwrap();
+        assert!(!ip_tools.check_ip_changed("1.1.1.1").await.unwrap());
+        assert!(!ip_tools.check_ip_changed("2400:3200::1").await.unwrap());
+        assert!(ip_tools.check_ip_changed("2400:3200::2").await.unwrap());
+        assert!(std::env::temp_dir()
+            .join("update_qcloud_firewall_families_ip_v6.txt")
+            .exists());
+    }
+
     #[tokio::test]
     async fn test_get_china_ip_address() {
         let tmp_file_path = std::env::temp_dir()
@@ -540,7 +938,7 @@ mod tests {
         let request_payload = String::from_utf8(buf).unwrap();
         let request: CreateDeleteFirewallRulesRequest =
             serde_json::from_str(&request_payload).unwrap();
-        let qcloud_tool = QCloudTool::new(None, None);
+        let qcloud_tool = QCloudTool::new(None, None, None, None).unwrap();
         qcloud_tool
             .create_firewall_rules("lhins-3jq1gki4", &request, "127.0.0.1")
             .await
@@ -556,13 +954,122 @@ mod tests {
         let request_payload = String::from_utf8(buf).unwrap();
         let request: CreateDeleteFirewallRulesRequest =
             serde_json::from_str(&request_payload).unwrap();
-        let qcloud_tool = QCloudTool::new(None, None);
+        let qcloud_tool = QCloudTool::new(None, None, None, None).unwrap();
         qcloud_tool
             .remove_firewall_rules("lhins-3jq1gki4", &request)
             .await
             .unwrap();
     }
 
+    const OK_RESPONSE: &str = r#"{"Response":{"RequestId":"req"}}"#;
+
+    #[test]
+    fn test_ip_info_of_ipv6_address() {
+        let json = r#"{"ip":"2400:3200::1","ip_decimal":47853221968737154008058251532656705537,"country":"China","country_iso":"CN","country_eu":false,"latitude":34.77,"longitude":113.72,"time_zone":"Asia/Shanghai","asn":"AS37963","asn_org":"Alibaba"}"#;
+        let info: IpInfo = serde_json::from_str(json).unwrap();
+        let ip: std::net::Ipv6Addr = "2400:3200::1".parse().unwrap();
+        assert_eq!(info.ip_decimal, u128::from(ip));
+    }
+
+    fn stand_in_tool(base_url: String) -> QCloudTool {
+        QCloudTool::new(
+            Some("id".to_string()),
+            Some("key".to_string()),
+            None,
+            Some(base_url),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_creates_before_deleting_stale_rules() {
+        let (base_url, handle) = stand_in_server(vec![
+            describe_response(&[("a", "1.1.1.1"), ("b", "1.1.1.1")]),
+            OK_RESPONSE.to_string(),
+            describe_response(&[
+                ("a", "1.1.1.1"),
+                ("b", "1.1.1.1"),
+                ("a", "2.2.2.2"),
+                ("b", "2.2.2.2"),
+            ]),
+            OK_RESPONSE.to_string(),
+        ])
+        .await;
+        let qcloud_tool = stand_in_tool(base_url);
+        qcloud_tool
+            .reconcile_firewall_rules(
+                "lhins-3jq1gki4",
+                &tpl(&["a", "b"]),
+                &PublicIps::from("2.2.2.2"),
+            )
+            .await
+            .unwrap();
+
+        let requests = handle.await.unwrap();
+        let actions: Vec<String> = requests.iter().map(|r| request_action(r)).collect();
+        assert_eq!(
+            actions,
+            [
+                "DescribeFirewallRules",
+                "CreateFirewallRules",
+                "DescribeFirewallRules",
+                "DeleteFirewallRules"
+            ]
+        );
+        assert!(requests[1].contains("2.2.2.2") && !requests[1].contains("1.1.1.1"));
+        assert!(requests[3].contains("1.1.1.1") && !requests[3].contains("2.2.2.2"));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_rolls_back_partial_create() {
+        let (base_url, handle) = stand_in_server(vec![
+            describe_response(&[("a", "1.1.1.1"), ("b", "1.1.1.1")]),
+            OK_RESPONSE.to_string(),
+            describe_response(&[("a", "1.1.1.1"), ("b", "1.1.1.1"), ("a", "2.2.2.2")]),
+            OK_RESPONSE.to_string(),
+        ])
+        .await;
+        let qcloud_tool = stand_in_tool(base_url);
+        let result = qcloud_tool
+            .reconcile_firewall_rules(
+                "lhins-3jq1gki4",
+                &tpl(&["a", "b"]),
+                &PublicIps::from("2.2.2.2"),
+            )
+            .await;
+        assert!(matches!(result, Err(QCloudError::Conflict(_))));
+
+        let requests = handle.await.unwrap();
+        assert_eq!(request_action(&requests[3]), "DeleteFirewallRules");
+        // only the created rule is rolled back, stale rules stay
+        assert!(requests[3].contains("2.2.2.2") && !requests[3].contains("1.1.1.1"));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_rolls_back_rules_created_by_failed_request() {
+        let (base_url, handle) = stand_in_server(vec![
+            describe_response(&[("a", "1.1.1.1"), ("b", "1.1.1.1")]),
+            r#"{"Response":{"Error":{"Code":"FailedOperation","Message":"Partly done"},"RequestId":"req"}}"#
+                .to_string(),
+            describe_response(&[("a", "1.1.1.1"), ("b", "1.1.1.1"), ("a", "2.2.2.2")]),
+            OK_RESPONSE.to_string(),
+        ])
+        .await;
+        let qcloud_tool = stand_in_tool(base_url);
+        let result = qcloud_tool
+            .reconcile_firewall_rules(
+                "lhins-3jq1gki4",
+                &tpl(&["a", "b"]),
+                &PublicIps::from("2.2.2.2"),
+            )
+            .await;
+        assert_eq!(result.unwrap_err().code(), Some("FailedOperation"));
+
+        let requests = handle.await.unwrap();
+        assert_eq!(request_action(&requests[3]), "DeleteFirewallRules");
+        assert!(requests[3].contains("2.2.2.2") && !requests[3].contains("1.1.1.1"));
+    }
+
     // #[test]
     // fn test_rust_native_reqwest_get() {
     //     tokio::runtime::Builder::new_multi_thread()
@@ -2,10 +2,13 @@ use std::{
     ffi::{CStr, CString},
     ops::Deref,
     os::raw::{c_char, c_void},
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
 };
 
 use crate::{
     c_struct::{IpConfigNative, UserAgentNative},
+    str_to_c_char_ptr, str_to_cstring, Config,
     web_client::{Callback, WebClient}, CallbackFirewall,
 };
 
@@ -16,7 +19,23 @@ pub extern "C" fn rust_version() -> *const c_char {
         .into_raw()
 }
 
+/// Borrow a C string as str. Returns None if it is null or not valid UTF-8.
+///
+/// # Safety
+///
+/// `c_str` must be null or a valid, nul terminated C string.
+unsafe fn cchar_to_str<'a>(c_str: *const c_char) -> Option<&'a str> {
+    if c_str.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(c_str) }.to_str().ok()
+}
+
 /// Create WebClient. For C, it creates a WebClient struct pointer
+///
+/// The state file is kept in the directory of `tmp_file_path` unless STATE_FILE variable
+/// names another file.
+/// Returns null if any argument is null or not valid UTF-8.
 /// # Safety
 #[no_mangle]
 pub unsafe extern "C" fn create_webapi_client(
@@ -25,22 +44,67 @@ pub unsafe extern "C" fn create_webapi_client(
     token_id: *const c_char,
     token_key: *const c_char,
 ) -> *mut WebClient {
-    let c_str_tmp_file_path = unsafe { CStr::from_ptr(tmp_file_path) };
-    let str_slice_tmp_file_path = c_str_tmp_file_path.to_str().unwrap();
-    let c_str_instance_id = unsafe { CStr::from_ptr(instance_id) };
-    let str_slice_instance_id = c_str_instance_id.to_str().unwrap();
-    let c_str_token_id = unsafe { CStr::from_ptr(token_id) };
-    let str_slice_token_id = c_str_token_id.to_str().unwrap();
-    let c_str_token_key = unsafe { CStr::from_ptr(token_key) };
-    let str_slice_token_key = c_str_token_key.to_str().unwrap();
+    let (
+        Some(str_slice_tmp_file_path),
+        Some(str_slice_instance_id),
+        Some(str_slice_token_id),
+        Some(str_slice_token_key),
+    ) = (
+        cchar_to_str(tmp_file_path),
+        cchar_to_str(instance_id),
+        cchar_to_str(token_id),
+        cchar_to_str(token_key),
+    )
+    else {
+        return std::ptr::null_mut();
+    };
     Box::into_raw(Box::new(WebClient::new(
         str_slice_tmp_file_path,
         str_slice_instance_id,
         str_slice_token_id,
         str_slice_token_key,
+        "",
+        "",
     )))
 }
 
+/// Create WebClient for a target of the configuration file. For C, it creates a WebClient
+/// struct pointer
+///
+/// `config_path` may be null to use `~/.config/update_qcloud_firewall/config.toml`. Besides
+/// the profile of the target, the ip detection, rate limits, state file and audit log of the
+/// configuration are used.
+/// Returns null if the configuration can't be loaded or doesn't define the target.
+/// # Safety
+#[no_mangle]
+pub unsafe extern "C" fn create_webapi_client_from_config(
+    tmp_file_path: *const c_char,
+    config_path: *const c_char,
+    target: *const c_char,
+) -> *mut WebClient {
+    let (Some(str_slice_tmp_file_path), Some(str_slice_target)) =
+        (cchar_to_str(tmp_file_path), cchar_to_str(target))
+    else {
+        return std::ptr::null_mut();
+    };
+    let config_path = if config_path.is_null() {
+        Config::default_path()
+    } else {
+        cchar_to_str(config_path).map(PathBuf::from)
+    };
+    let Some(config_path) = config_path else {
+        return std::ptr::null_mut();
+    };
+    match WebClient::from_config(str_slice_tmp_file_path, &config_path, str_slice_target) {
+        Ok(client) => Box::into_raw(Box::new(client)),
+        Err(err) => {
+            // there is no callback yet to report the error through
+            eprintln!("Error: {}", err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
 /// .
 ///
 /// # Panics
@@ -126,39 +190,37 @@ impl Deref for FirewallCallback {
     }
 }
 
-/// .
+/// Get the public ip config through `outer_listener`
 ///
-/// # Panics
-///
-/// Panics if .
+/// A null client is reported through `onError`.
 ///
 /// # Safety
 ///
-/// .
+/// `client` must be null or a client created by this library and not freed yet.
 #[no_mangle]
 pub unsafe extern "C" fn get_ip_config_native(
     client: *mut WebClient,
     outer_listener: IpConfigCallback,
 ) {
-    assert!(!client.is_null());
+    let Some(local_client) = client.as_ref() else {
+        let error_message = str_to_c_char_ptr("Failed to get ip config. Err: client is null");
+        (outer_listener.onError)(outer_listener.owner, error_message);
+        return;
+    };
 
-    let local_client = client.as_ref().unwrap();
     let cb = Callback {
         result: Box::new(move |result| {
             let has_user_agent = result.user_agent.is_some();
-            let mut user_agent_native: UserAgentNative = if result.user_agent.is_some() {
-                let user_agent = result.user_agent.unwrap();
+            let mut user_agent_native: UserAgentNative = if let Some(user_agent) = result.user_agent {
                 UserAgentNative {
-                    product: if user_agent.product.is_some() {
-                        CString::new(user_agent.product.unwrap())
-                            .unwrap()
-                            .into_raw()
+                    product: if let Some(product) = user_agent.product {
+                        str_to_c_char_ptr(&product)
                     } else {
                         std::ptr::null()
                     },
-                    comment: CString::new(user_agent.comment).unwrap().into_raw(),
-                    version: CString::new(user_agent.version).unwrap().into_raw(),
-                    raw_value: CString::new(user_agent.raw_value).unwrap().into_raw(),
+                    comment: str_to_c_char_ptr(&user_agent.comment),
+                    version: str_to_c_char_ptr(&user_agent.version),
+                    raw_value: str_to_c_char_ptr(&user_agent.raw_value),
                 }
             } else {
                 UserAgentNative {
@@ -170,16 +232,16 @@ pub unsafe extern "C" fn get_ip_config_native(
             };
 
             let ip_config_native: IpConfigNative = IpConfigNative {
-                ip: CString::new(result.ip).unwrap().into_raw(),
-                ip_decimal: result.ip_decimal,
-                country: CString::new(result.country).unwrap().into_raw(),
-                country_iso: CString::new(result.country_iso).unwrap().into_raw(),
+                ip: str_to_c_char_ptr(&result.ip),
+                ip_decimal: str_to_c_char_ptr(&result.ip_decimal.to_string()),
+                country: str_to_c_char_ptr(&result.country),
+                country_iso: str_to_c_char_ptr(&result.country_iso),
                 country_eu: u8::from(result.country_eu),
                 latitude: result.latitude,
                 longitude: result.longitude,
-                time_zone: CString::new(result.time_zone).unwrap().into_raw(),
-                asn: CString::new(result.asn).unwrap().into_raw(),
-                asn_org: CString::new(result.asn_org).unwrap().into_raw(),
+                time_zone: str_to_c_char_ptr(&result.time_zone),
+                asn: str_to_c_char_ptr(&result.asn),
+                asn_org: str_to_c_char_ptr(&result.asn_org),
                 user_agent: if has_user_agent {
                     &mut user_agent_native
                 } else {
@@ -190,12 +252,16 @@ pub unsafe extern "C" fn get_ip_config_native(
             (outer_listener.onResult)(outer_listener.owner, &ip_config_native);
         }),
         error: Box::new(move |error| {
-            let error_message = CString::new(error).unwrap().into_raw();
+            let error_message = str_to_c_char_ptr(&error);
             (outer_listener.onError)(outer_listener.owner, error_message);
         }),
     };
     let callback = Box::new(cb);
-    local_client.getIpConfig(callback);
+    // a panic must not unwind across extern "C"
+    if panic::catch_unwind(AssertUnwindSafe(|| local_client.getIpConfig(callback))).is_err() {
+        let error_message = str_to_c_char_ptr("Failed to get ip config. Err: unexpected panic");
+        (outer_listener.onError)(outer_listener.owner, error_message);
+    }
 }
 
 // /// call qcloud webapi to check if current public ip is allow from firewall
@@ -203,30 +269,35 @@ pub unsafe extern "C" fn get_ip_config_native(
 //     1
 // }
 
-/// .
+/// Recreate the firewall rules of `payload` for the current public ip through
+/// `outer_listener`
 ///
-/// # Panics
-///
-/// Panics if .
+/// A null client or an invalid payload is reported through `onError`.
 ///
 /// # Safety
 ///
-/// .
+/// `client` must be null or a client created by this library and not freed yet.
 #[no_mangle]
 pub unsafe extern "C" fn recreate_firewall_policy(
     client: *mut WebClient,
     payload: *const c_char,
     outer_listener: FirewallCallback,
 ) {
-    assert!(!client.is_null());
-    
-    let c_str_payload = unsafe { CStr::from_ptr(payload) };
-    let str_slice_payload = c_str_payload.to_str().unwrap();
+    let Some(local_client) = client.as_ref() else {
+        let err_msg = str_to_cstring("Failed to recreate firewall policy. Err: client is null");
+        (outer_listener.onError)(outer_listener.owner, err_msg.as_ptr());
+        return;
+    };
+
+    let Some(str_slice_payload) = cchar_to_str(payload) else {
+        let err_msg = str_to_cstring("Invalid payload. Err: payload is null or not valid UTF-8");
+        (outer_listener.onError)(outer_listener.owner, err_msg.as_ptr());
+        return;
+    };
 
-    let local_client = client.as_ref().unwrap();
     let cb = CallbackFirewall {
         result: Box::new(move |result| {
-            let message = CString::new(result).unwrap();
+            let message = str_to_cstring(&result);
             // we don't want to use into_raw to leak the memory of message into C. In this way
             // (as_ptr), rust will finally release CString memory. Otherwise we have to call
             // free_string(xx) from C code to manually release rust CString
@@ -234,13 +305,21 @@ pub unsafe extern "C" fn recreate_firewall_policy(
             (outer_listener.onResult)(outer_listener.owner, ptr);
         }),
         error: Box::new(move |error| {
-            let err_msg = CString::new(error).unwrap();
+            let err_msg = str_to_cstring(&error);
             let ptr = err_msg.as_ptr();
             (outer_listener.onError)(outer_listener.owner, ptr);
         }),
     };
     let callback = Box::new(cb);
-    local_client.recreateFirewallPolicy(str_slice_payload, callback);
+    // a panic must not unwind across extern "C"
+    if panic::catch_unwind(AssertUnwindSafe(|| {
+        local_client.recreateFirewallPolicy(str_slice_payload, callback)
+    }))
+    .is_err()
+    {
+        let err_msg = str_to_cstring("Failed to recreate firewall policy. Err: unexpected panic");
+        (outer_listener.onError)(outer_listener.owner, err_msg.as_ptr());
+    }
 }
 
 // #[cfg(test)]